@@ -37,6 +37,10 @@
           settings = {
             listen = "0.0.0.0:3001";
             audience = "http://server:3000";
+            issuers = [{
+              issuer = "http://server:3002";
+              allow_insecure_http = true;
+            }];
             policies = [{
               issuer = "http://server:3002";
               duration = "1h";
@@ -23,6 +23,10 @@
           settings = {
             listen = "[::]:3001";
             audience = "http://server:3001";
+            issuers = [{
+              issuer = "http://server:3002";
+              allow_insecure_http = true;
+            }];
             policies = [{
               issuer = "http://server:3002";
               duration = "1h";
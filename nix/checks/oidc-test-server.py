@@ -13,9 +13,14 @@ KEY['alg'] = 'RS256'
 KEYSET = JWKSet(keys=KEY)
 
 
+def issuer():
+    return urlunsplit(bottle.request.urlparts._replace(path="", query="", fragment=""))
+
+
 @bottle.route("/.well-known/openid-configuration")
 def oidc_configuration():
     return {
+        "issuer": issuer(),
         "jwks_uri": urljoin(bottle.request.url, "/jwks.json"),
     }
 
@@ -28,8 +33,7 @@ def jwks():
 @bottle.post("/token")
 def token():
     claims = bottle.request.json
-    iss = urlunsplit(bottle.request.urlparts._replace(path=""))
-    claims.setdefault("iss", iss)
+    claims.setdefault("iss", issuer())
     claims.setdefault("exp", int(datetime.now(UTC).timestamp()) + 3600)
     jwt = JWT(header={"alg": "RS256", "kid": KEY["kid"]}, claims=claims)
     jwt.make_signed_token(KEY)
@@ -0,0 +1,128 @@
+{ self, moduleWithSystem, ... }: {
+  perSystem = { pkgs, ... }: {
+    checks.policies = pkgs.testers.runNixOSTest {
+      name = "policies";
+
+      nodes.server = moduleWithSystem ({ self', ... }: { pkgs, ... }: {
+        imports = [
+          self.nixosModules.attic-trusted-publisher
+          self.nixosModules.oidc-test-server
+        ];
+        networking.firewall.enable = false;
+        services.atticd = {
+          enable = true;
+          settings.listen = "[::]:3000";
+          environmentFile = pkgs.runCommand "envfile" { } ''
+            cat > $out <<EOF
+            ATTIC_SERVER_TOKEN_HS256_SECRET_BASE64=$(echo -n "secret" | base64)
+            EOF
+          '';
+        };
+        services.attic-trusted-publisher = {
+          enable = true;
+          settings = {
+            listen = "[::]:3001";
+            audience = "http://server:3001";
+            issuers = [{
+              issuer = "http://server:3002";
+              allow_insecure_http = true;
+              single_use = true;
+            }];
+            policies = [
+              {
+                name = "deny-mallory";
+                issuer = "http://server:3002";
+                effect = "deny";
+                required_claims.actor = "mallory";
+              }
+              {
+                name = "owner";
+                issuer = "http://server:3002";
+                duration = "1h";
+                required_claims.repository_owner_id = "1234";
+                condition.any = [
+                  { claims.ref = { glob = "refs/heads/*"; }; }
+                  { cel = "claims.ref_type == 'tag'"; }
+                ];
+                subject = "repo:{repository}";
+                permissions."gh-{repository_owner_id}-{repository_name}" = {
+                  pull = true;
+                  push = true;
+                  create_cache = true;
+                };
+              }
+            ];
+          };
+        };
+        services.oidc-test-server = {
+          enable = true;
+          port = 3002;
+        };
+      });
+
+      nodes.client = moduleWithSystem ({ self', ... }: { pkgs, ... }: {
+        environment.systemPackages = [
+          self'.packages.attic-trusted-publisher
+          pkgs.attic-client
+        ];
+      });
+
+      testScript = ''
+        import json
+
+        start_all()
+        server.wait_for_unit("default.target")
+        client.wait_for_unit("default.target")
+
+        server.wait_for_open_port(3000)
+        server.wait_for_open_port(3001)
+        server.wait_for_open_port(3002)
+
+        def id_token(**overrides):
+            claims = {
+                "aud": "http://server:3001",
+                "repository_owner_id": "1234",
+                "repository": "owner/repo",
+                "repository_name": "repo",
+                "ref": "refs/heads/main",
+                "actor": "alice",
+                **overrides,
+            }
+            return client.succeed(
+                f"curl -sfL http://server:3002/token --json '{json.dumps(claims)}'"
+            ).strip()
+
+        def exchange(token):
+            return client.succeed(
+                f"curl -sL http://server:3001/_trusted-publisher/token --json '{json.dumps({'token': token})}'"
+            )
+
+        with subtest("matching token can use its templated cache"):
+            token = id_token(jti="1")
+            client.succeed(f"""
+              attictoken=$(attic-trusted-publisher login http://server:3001 {token})
+              attic login default http://server:3000 "$attictoken"
+            """)
+            client.succeed("attic cache create gh-1234-repo")
+            client.fail("attic cache create other")
+
+        with subtest("single-use token cannot be exchanged twice"):
+            assert "already been exchanged" in exchange(token)
+
+        with subtest("tags are accepted through the CEL condition"):
+            client.succeed(f"attic-trusted-publisher login http://server:3001 {id_token(jti='2', ref='refs/tags/v1', ref_type='tag')}")
+
+        with subtest("deny policy takes precedence"):
+            response = exchange(id_token(jti="3", actor="mallory"))
+            assert "not authorized" in response, response
+
+        with subtest("claims cannot inject characters into cache names"):
+            client.fail(f"attic-trusted-publisher login http://server:3001 {id_token(jti='4', repository_name='*')}")
+            client.fail(f"attic-trusted-publisher login http://server:3001 {id_token(jti='5', repository_name='a/b')}")
+
+        with subtest("condition is required"):
+            client.fail(f"attic-trusted-publisher login http://server:3001 {id_token(jti='6', ref='refs/pull/1/merge')}")
+      '';
+    };
+  };
+}
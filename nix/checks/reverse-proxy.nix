@@ -26,6 +26,10 @@
           settings = {
             listen = "127.0.0.1:3001";
             audience = "http://server";
+            issuers = [{
+              issuer = "http://server:3002";
+              allow_insecure_http = true;
+            }];
             policies = [{
               issuer = "http://server:3002";
               duration = "1h";
@@ -37,6 +37,7 @@
               ExecStart = "${lib.getExe self'.packages.attic-trusted-publisher} api --config ${configFile}";
               EnvironmentFile = lib.mkIf (cfg.environmentFile != null) cfg.environmentFile;
               DynamicUser = true;
+              StateDirectory = "attic-trusted-publisher";
               User = cfg.user;
               Group = cfg.group;
             };
@@ -8,6 +8,13 @@ use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tracing::Level;
 
 use crate::Config;
+use crate::config::ErrorResponses;
+use crate::verifier::Verifier;
+
+struct AppState {
+    config: Config,
+    verifier: Verifier,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenRequest {
@@ -17,11 +24,20 @@ pub struct TokenRequest {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenResponse {
     pub token: String,
+
+    /// The names of the policies which granted the token, or their positions for unnamed ones.
+    #[serde(default)]
+    pub policies: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ApiError {
     pub error: String,
+
+    /// Identifies the failure in the server's logs, when the details are not included in `error`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
 }
 
 impl IntoResponse for crate::Error {
@@ -30,6 +46,7 @@ impl IntoResponse for crate::Error {
             StatusCode::UNAUTHORIZED,
             Json(ApiError {
                 error: self.to_string(),
+                correlation_id: None,
             }),
         )
             .into_response()
@@ -38,15 +55,36 @@ impl IntoResponse for crate::Error {
 
 #[axum::debug_handler]
 async fn token_endpoint(
-    State(config): State<Arc<Config>>,
+    State(state): State<Arc<AppState>>,
     Json(request): Json<TokenRequest>,
-) -> Result<Json<TokenResponse>, crate::Error> {
-    crate::exchange(&request.token, &config)
-        .await
-        .map(|token| Json(TokenResponse { token }))
+) -> Result<Json<TokenResponse>, Response> {
+    match crate::exchange(&request.token, &state.config, &state.verifier).await {
+        Ok(response) => Ok(Json(response)),
+        Err(err) => match state.config.error_responses {
+            ErrorResponses::Verbose => Err(err.into_response()),
+            ErrorResponses::Redacted => {
+                let correlation_id = uuid::Uuid::new_v4().to_string();
+                tracing::warn!(correlation_id, "token exchange failed: {err:#}");
+                let error = if err.describes_policies() {
+                    "not authorized".into()
+                } else {
+                    err.to_string()
+                };
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    Json(ApiError {
+                        error,
+                        correlation_id: Some(correlation_id),
+                    }),
+                )
+                    .into_response())
+            }
+        },
+    }
 }
 
 pub async fn run(config: Config) -> anyhow::Result<()> {
+    let verifier = Verifier::new(&config)?;
     let listener = tokio::net::TcpListener::bind(&config.listen).await?;
     let app = Router::new()
         .route("/_trusted-publisher/token", post(token_endpoint))
@@ -55,7 +93,7 @@ pub async fn run(config: Config) -> anyhow::Result<()> {
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
-        .with_state(Arc::new(config));
+        .with_state(Arc::new(AppState { config, verifier }));
     axum::serve(listener, app).await?;
     Ok(())
 }
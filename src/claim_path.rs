@@ -0,0 +1,161 @@
+use anyhow::{Context, bail, ensure};
+use std::fmt;
+
+/// The location of a claim, possibly nested inside objects or arrays.
+///
+/// Paths starting with a `/` are JSON Pointers (RFC 6901), eg. `/kubernetes.io/namespace`. Other
+/// paths are dot-separated, eg. `kubernetes\.io.namespace`, where `\.` and `\\` stand for a
+/// literal dot and backslash. In both forms, a numeric segment indexes into an array.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClaimPath {
+    source: String,
+    segments: Vec<String>,
+}
+
+impl ClaimPath {
+    pub fn parse(source: &str) -> anyhow::Result<ClaimPath> {
+        let segments = if let Some(pointer) = source.strip_prefix('/') {
+            pointer
+                .split('/')
+                .map(parse_pointer_segment)
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("invalid JSON pointer `{source}`"))?
+        } else {
+            parse_dotted(source).with_context(|| format!("invalid claim path `{source}`"))?
+        };
+        Ok(ClaimPath {
+            source: source.to_owned(),
+            segments,
+        })
+    }
+
+    /// The name of the top-level claim.
+    pub fn claim(&self) -> &str {
+        &self.segments[0]
+    }
+
+    /// Whether the path refers to a whole top-level claim, rather than a value nested inside it.
+    pub fn is_top_level(&self) -> bool {
+        self.segments.len() == 1
+    }
+
+    /// Walk the remainder of the path, starting from the value of the top-level claim.
+    pub fn resolve<'a>(&self, value: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.segments[1..]
+            .iter()
+            .try_fold(value, |value, segment| match value {
+                serde_json::Value::Object(map) => map.get(segment),
+                serde_json::Value::Array(array) => array.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+}
+
+fn parse_pointer_segment(segment: &str) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(segment.len());
+    let mut chars = segment.chars();
+    while let Some(c) = chars.next() {
+        if c == '~' {
+            match chars.next() {
+                Some('0') => result.push('~'),
+                Some('1') => result.push('/'),
+                _ => bail!("`~` must be followed by `0` or `1`"),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Ok(result)
+}
+
+fn parse_dotted(source: &str) -> anyhow::Result<Vec<String>> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('.' | '\\')) => current.push(c),
+                _ => bail!("`\\` must be followed by `.` or `\\`"),
+            },
+            '.' => segments.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    segments.push(current);
+    ensure!(
+        segments.iter().all(|s| !s.is_empty()),
+        "path segments cannot be empty"
+    );
+    Ok(segments)
+}
+
+impl fmt::Display for ClaimPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn resolve(path: &str, claims: &serde_json::Value) -> Option<serde_json::Value> {
+        let path = ClaimPath::parse(path).unwrap();
+        let value = claims.get(path.claim())?;
+        path.resolve(value).cloned()
+    }
+
+    #[test]
+    fn dotted_paths() {
+        let claims = json!({
+            "a.b": { "c": 1 },
+            "a": { "b": { "c": 2 } },
+            "x\\y": 3,
+            "list": [{ "name": "first" }],
+        });
+        assert_eq!(resolve("a\\.b.c", &claims), Some(json!(1)));
+        assert_eq!(resolve("a.b.c", &claims), Some(json!(2)));
+        assert_eq!(resolve("x\\\\y", &claims), Some(json!(3)));
+        assert_eq!(resolve("list.0.name", &claims), Some(json!("first")));
+        assert_eq!(resolve("list.1.name", &claims), None);
+        assert_eq!(resolve("list.name", &claims), None);
+        assert_eq!(resolve("a.b.c.d", &claims), None);
+    }
+
+    #[test]
+    fn pointer_paths() {
+        let claims = json!({
+            "a/b": 1,
+            "a~b": 2,
+            "kubernetes.io": { "namespace": "default" },
+            "list": ["first"],
+        });
+        assert_eq!(resolve("/a~1b", &claims), Some(json!(1)));
+        assert_eq!(resolve("/a~0b", &claims), Some(json!(2)));
+        assert_eq!(
+            resolve("/kubernetes.io/namespace", &claims),
+            Some(json!("default"))
+        );
+        assert_eq!(resolve("/list/0", &claims), Some(json!("first")));
+        assert_eq!(resolve("/a~01b", &claims), None);
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in ["", "a..b", "a.", ".a", "a\\b", "a\\", "/a~2", "/a~"] {
+            assert!(
+                ClaimPath::parse(path).is_err(),
+                "{path:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn top_level() {
+        assert!(ClaimPath::parse("aud").unwrap().is_top_level());
+        assert!(ClaimPath::parse("/aud").unwrap().is_top_level());
+        assert!(!ClaimPath::parse("a.b").unwrap().is_top_level());
+    }
+}
@@ -21,7 +21,10 @@ impl CheckError for reqwest::Response {
 
             if is_json {
                 let body: ApiError = self.json().await?;
-                bail!("{status}: {}", body.error);
+                match body.correlation_id {
+                    Some(id) => bail!("{status}: {} (correlation ID: {id})", body.error),
+                    None => bail!("{status}: {}", body.error),
+                }
             } else {
                 bail!("{status}: {}", self.text().await?);
             }
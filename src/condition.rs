@@ -0,0 +1,58 @@
+use crate::claim_path::ClaimPath;
+use crate::expression::Expression;
+use crate::matcher::{ClaimMatcher, deserialize_claim_matchers};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// A boolean combination of claim matchers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Condition {
+    /// All of the conditions must hold.
+    All(Vec<Condition>),
+
+    /// At least one of the conditions must hold.
+    Any(Vec<Condition>),
+
+    /// The condition must not hold.
+    Not(Box<Condition>),
+
+    /// All of the claims must be present and match, like a policy's `required_claims`.
+    Claims(
+        #[serde(deserialize_with = "deserialize_claim_matchers")] HashMap<ClaimPath, ClaimMatcher>,
+    ),
+
+    /// A CEL expression over the `claims` map, which must evaluate to `true`.
+    Cel(Expression),
+}
+
+impl Condition {
+    /// Whether the condition constrains the token at all, as opposed to being trivially true.
+    ///
+    /// This errs on the side of caution: some conditions which can never hold are also considered
+    /// to be unconstrained.
+    pub fn has_constraints(&self) -> bool {
+        match self {
+            Condition::All(conditions) => conditions.iter().any(|c| c.has_constraints()),
+            Condition::Any(conditions) => {
+                !conditions.is_empty() && conditions.iter().all(|c| c.has_constraints())
+            }
+            Condition::Not(condition) => condition.has_constraints(),
+            Condition::Claims(claims) => !claims.is_empty(),
+            Condition::Cel(expression) => expression.has_constraints(),
+        }
+    }
+
+    /// All of the claim matchers used in the condition.
+    pub fn claim_matchers(&self) -> Vec<(&ClaimPath, &ClaimMatcher)> {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                conditions.iter().flat_map(|c| c.claim_matchers()).collect()
+            }
+            Condition::Not(condition) => condition.claim_matchers(),
+            Condition::Claims(claims) => claims.iter().collect(),
+            Condition::Cel(_) => Vec::new(),
+        }
+    }
+}
@@ -1,11 +1,18 @@
+use crate::claim_path::ClaimPath;
+use crate::condition::Condition;
+use crate::matcher::{ClaimMatcher, deserialize_claim_matchers};
+use crate::template::Template;
+use anyhow::Context;
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64_STANDARD};
 use itertools::Itertools;
-use jsonwebtoken::{Algorithm, EncodingKey};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
 use serde::Deserialize;
 use serde::de;
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize, better_default::Default)]
@@ -28,6 +35,22 @@ pub struct Permissions {
     pub destroy_cache: bool,
 }
 
+impl Permissions {
+    /// Combine two sets of permissions, granting everything that either of them grants.
+    pub fn union(&self, other: &Permissions) -> Permissions {
+        Permissions {
+            pull: self.pull || other.pull,
+            push: self.push || other.push,
+            delete: self.delete || other.delete,
+            create_cache: self.create_cache || other.create_cache,
+            configure_cache: self.configure_cache || other.configure_cache,
+            configure_cache_retention: self.configure_cache_retention
+                || other.configure_cache_retention,
+            destroy_cache: self.destroy_cache || other.destroy_cache,
+        }
+    }
+}
+
 fn get_false() -> bool {
     false
 }
@@ -35,15 +58,166 @@ fn get_false() -> bool {
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Policy {
+    /// Identifies the policy in logs, errors and exchange responses.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Whether matching tokens are allowed or denied. Deny policies take precedence.
+    #[serde(default)]
+    pub effect: Effect,
+
+    /// Lifespan of the issued tokens. Required for allow policies.
     #[serde(with = "humantime_serde")]
+    #[serde(default)]
     pub duration: Duration,
     pub issuer: String,
-    pub permissions: HashMap<String, Permissions>,
+
+    /// Permissions granted for each cache. Cache names may refer to claims, eg. `gh-{repository_id}`.
+    #[serde(default)]
+    pub permissions: HashMap<Template, Permissions>,
+
+    /// If set, the `sub` claim of the issued token is rendered from this template instead of
+    /// being copied from the ID token.
+    #[serde(default)]
+    pub subject: Option<Template>,
 
     #[serde(default = "get_false")]
     pub allow_extending_token_lifespan: bool,
 
-    pub required_claims: HashMap<String, serde_json::Value>,
+    /// If set, the exchange fails rather than issue a token that expires sooner than this.
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub min_remaining_lifetime: Option<Duration>,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_claim_matchers")]
+    pub required_claims: HashMap<ClaimPath, ClaimMatcher>,
+
+    /// Additional constraints, combined using `all`, `any` and `not`.
+    #[serde(default)]
+    pub condition: Option<Condition>,
+
+    /// Position of the policy in the configuration file, used to identify it in messages.
+    #[serde(skip)]
+    pub index: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Effect {
+    #[default]
+    #[serde(rename = "allow")]
+    Allow,
+
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+/// The issuer of GitHub Actions ID tokens.
+const GITHUB_ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+/// Claims of GitHub Actions ID tokens which look like numbers but are encoded as strings.
+const GITHUB_NUMERIC_STRING_CLAIMS: &[&str] = &[
+    "actor_id",
+    "enterprise_id",
+    "repository_id",
+    "repository_owner_id",
+    "run_attempt",
+    "run_id",
+    "run_number",
+];
+
+impl Policy {
+    /// The policy's name, or its position in the configuration file if it has none.
+    pub fn identifier(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", self.index + 1),
+        }
+    }
+
+    /// Describes the policy in messages.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("policy `{name}`"),
+            None => format!("policy #{}", self.index + 1),
+        }
+    }
+
+    /// Whether the policy constrains the token at all. Policies which do not are never applied.
+    pub fn has_constraints(&self) -> bool {
+        !self.required_claims.is_empty()
+            || self.condition.as_ref().is_some_and(|c| c.has_constraints())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.name.as_deref() == Some("") {
+            anyhow::bail!("policy #{} has an empty `name`", self.index + 1);
+        }
+
+        match self.effect {
+            Effect::Allow => {
+                if self.duration.is_zero() {
+                    anyhow::bail!("{} must have a non-zero `duration`", self.label());
+                }
+            }
+            Effect::Deny => {
+                // Such a policy would never match, which is surprising for a deny policy.
+                if !self.has_constraints() {
+                    anyhow::bail!(
+                        "{} is a deny policy and must have `required_claims` or a `condition` that constrains the token",
+                        self.label()
+                    );
+                }
+                if !self.duration.is_zero()
+                    || !self.permissions.is_empty()
+                    || self.subject.is_some()
+                    || self.allow_extending_token_lifespan
+                    || self.min_remaining_lifetime.is_some()
+                {
+                    anyhow::bail!(
+                        "{} is a deny policy and cannot set `duration`, `permissions`, `subject`, `allow_extending_token_lifespan` or `min_remaining_lifetime`",
+                        self.label()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn about comparisons of GitHub's string-encoded IDs with number literals, which can
+    /// never succeed.
+    fn check_github_numeric_claims(&self) {
+        if self.issuer != GITHUB_ISSUER {
+            return;
+        }
+
+        let condition_matchers = self.condition.iter().flat_map(|c| c.claim_matchers());
+        for (path, matcher) in self.required_claims.iter().chain(condition_matchers) {
+            if GITHUB_NUMERIC_STRING_CLAIMS.contains(&path.claim())
+                && matcher.compares_number_literal()
+            {
+                tracing::warn!(
+                    "{} compares claim `{path}` with a number, but GitHub encodes it as a string and it will never match; use a string or `{{ number = ... }}` instead",
+                    self.label()
+                );
+            }
+        }
+    }
+
+    /// Warn about comparisons of the `aud` claim with a single audience, which used to match
+    /// tokens with a single audience but can never succeed now that `aud` is always a list.
+    fn check_audience_claims(&self) {
+        let condition_matchers = self.condition.iter().flat_map(|c| c.claim_matchers());
+        for (path, matcher) in self.required_claims.iter().chain(condition_matchers) {
+            if path.claim() == "aud" && path.is_top_level() && matcher.compares_non_array_literal()
+            {
+                tracing::warn!(
+                    "{} compares claim `aud` with a single value, but `aud` is always a list so the comparison never succeeds; use `{{ contains = ... }}` instead",
+                    self.label()
+                );
+            }
+        }
+    }
 }
 
 fn deserialize_policies<'de, D>(d: D) -> Result<HashMap<String, Vec<Policy>>, D::Error>
@@ -51,7 +225,231 @@ where
     D: serde::de::Deserializer<'de>,
 {
     let policies: Vec<Policy> = Deserialize::deserialize(d)?;
-    Ok(policies.into_iter().into_group_map_by(|p| p.issuer.clone()))
+    Ok(policies
+        .into_iter()
+        .enumerate()
+        .map(|(index, policy)| Policy { index, ..policy })
+        .into_group_map_by(|p| p.issuer.clone()))
+}
+
+fn is_https(url: &str) -> anyhow::Result<bool> {
+    let url = reqwest::Url::parse(url).with_context(|| format!("invalid URL `{url}`"))?;
+    Ok(url.scheme() == "https")
+}
+
+/// Keys used to verify an issuer's tokens, configured statically instead of being discovered.
+#[derive(Clone, Debug, Deserialize)]
+pub enum IssuerKeys {
+    #[serde(rename = "jwks")]
+    Jwks(JwkSet),
+
+    #[serde(rename = "jwks_file")]
+    #[serde(deserialize_with = "deserialize_jwks_file")]
+    JwksFile(JwkSet),
+
+    #[serde(rename = "public_key")]
+    PublicKey(PublicKey),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "PublicKeyConfig")]
+pub struct PublicKey {
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    pub key: DecodingKey,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PublicKeyConfig {
+    #[serde(default)]
+    kid: Option<String>,
+    algorithm: Algorithm,
+    pem: String,
+}
+
+impl TryFrom<PublicKeyConfig> for PublicKey {
+    type Error = jsonwebtoken::errors::Error;
+
+    fn try_from(config: PublicKeyConfig) -> Result<Self, Self::Error> {
+        let pem = config.pem.as_bytes();
+        let key = match config.algorithm {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem)?,
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem)?,
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(pem)?,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                return Err(jsonwebtoken::errors::ErrorKind::InvalidAlgorithm.into());
+            }
+        };
+        Ok(PublicKey {
+            kid: config.kid,
+            algorithm: config.algorithm,
+            key,
+        })
+    }
+}
+
+fn default_leeway() -> Duration {
+    // Same as jsonwebtoken's default
+    Duration::from_secs(60)
+}
+
+fn default_http_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IssuerConfig {
+    pub issuer: String,
+
+    /// URL of the OIDC discovery document. Defaults to `{issuer}/.well-known/openid-configuration`.
+    #[serde(default)]
+    pub discovery_url: Option<String>,
+
+    /// If set, keys are fetched from this URL and OIDC discovery is skipped.
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+
+    /// Allow keys to be fetched over plain HTTP.
+    #[serde(default = "get_false")]
+    pub allow_insecure_http: bool,
+
+    /// Allow the discovery document to point at a `jwks_uri` on a different origin than the
+    /// issuer's.
+    #[serde(default = "get_false")]
+    pub allow_cross_origin_jwks_uri: bool,
+
+    /// If set, tokens are verified using these keys instead of the ones advertised by the
+    /// issuer through OIDC discovery.
+    #[serde(default)]
+    pub keys: Option<IssuerKeys>,
+
+    /// Signing algorithms accepted for this issuer's tokens. If unset, only asymmetric
+    /// algorithms are accepted.
+    #[serde(default)]
+    pub algorithms: Option<HashSet<Algorithm>>,
+
+    /// Clock skew tolerated when validating the `exp`, `nbf` and `iat` claims.
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_leeway")]
+    pub leeway: Duration,
+
+    /// If set, each token can only be exchanged once.
+    #[serde(default = "get_false")]
+    pub single_use: bool,
+
+    /// If set, tokens must have an `iat` claim and must have been issued less than this long ago.
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub max_token_age: Option<Duration>,
+
+    /// Timeout for requests made to the issuer.
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_http_timeout")]
+    pub http_timeout: Duration,
+}
+
+impl IssuerConfig {
+    /// The configuration used for issuers that are referenced by a policy but do not have an
+    /// explicit entry in the `issuers` list.
+    pub fn new(issuer: &str) -> IssuerConfig {
+        IssuerConfig {
+            issuer: issuer.to_owned(),
+            discovery_url: None,
+            jwks_uri: None,
+            allow_insecure_http: false,
+            allow_cross_origin_jwks_uri: false,
+            keys: None,
+            algorithms: None,
+            leeway: default_leeway(),
+            single_use: false,
+            max_token_age: None,
+            http_timeout: default_http_timeout(),
+        }
+    }
+
+    pub fn discovery_url(&self) -> String {
+        match &self.discovery_url {
+            Some(url) => url.clone(),
+            None => format!("{}/.well-known/openid-configuration", self.issuer),
+        }
+    }
+
+    pub fn allows_algorithm(&self, algorithm: Algorithm) -> bool {
+        match &self.algorithms {
+            Some(algorithms) => algorithms.contains(&algorithm),
+            // Symmetric keys have no business being published in a JWKS. Accepting them would
+            // allow anyone who can read the JWKS to forge tokens.
+            None => !crate::verifier::is_symmetric(algorithm),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.allow_insecure_http {
+            let urls = [
+                Some(&self.issuer),
+                self.discovery_url.as_ref(),
+                self.jwks_uri.as_ref(),
+            ];
+            for url in urls.into_iter().flatten() {
+                if !is_https(url)? {
+                    anyhow::bail!(
+                        "issuer `{}` uses insecure URL `{url}`, set `allow_insecure_http = true` to allow this",
+                        self.issuer
+                    );
+                }
+            }
+        }
+        if self.keys.is_some() && (self.discovery_url.is_some() || self.jwks_uri.is_some()) {
+            anyhow::bail!(
+                "issuer `{}` has static keys and cannot also set `discovery_url` or `jwks_uri`",
+                self.issuer
+            );
+        }
+        if self.algorithms.as_ref().is_some_and(|a| a.is_empty()) {
+            anyhow::bail!("issuer `{}` has an empty list of algorithms", self.issuer);
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_issuers<'de, D>(d: D) -> Result<HashMap<String, IssuerConfig>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let issuers: Vec<IssuerConfig> = Deserialize::deserialize(d)?;
+    let mut result = HashMap::new();
+    for issuer in issuers {
+        if result.contains_key(&issuer.issuer) {
+            return Err(Error::custom(format!(
+                "issuer `{}` is configured more than once",
+                issuer.issuer
+            )));
+        }
+        result.insert(issuer.issuer.clone(), issuer);
+    }
+    Ok(result)
+}
+
+fn deserialize_jwks_file<'de, D>(deserializer: D) -> Result<JwkSet, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let path = PathBuf::deserialize(deserializer)?;
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::custom(format!("cannot read {}: {e}", path.display())))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| Error::custom(format!("cannot parse {}: {e}", path.display())))
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -65,8 +463,121 @@ pub struct Config {
     #[serde(deserialize_with = "deserialize_policies")]
     pub policies: HashMap<String, Vec<Policy>>,
 
+    #[serde(default)]
+    pub match_mode: MatchMode,
+
+    #[serde(default)]
+    pub error_responses: ErrorResponses,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_issuers")]
+    pub issuers: HashMap<String, IssuerConfig>,
+
     #[serde(default)]
     pub jwt: JWTConfig,
+
+    #[serde(default)]
+    pub replay_store: ReplayStoreConfig,
+}
+
+/// How much detail to include in the token endpoint's error responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum ErrorResponses {
+    /// Respond with a generic error and a correlation ID, and only log the details. The details
+    /// would otherwise reveal the claims required by the configured policies.
+    #[default]
+    #[serde(rename = "redacted")]
+    Redacted,
+
+    /// Include the details in the response, which is useful when writing policies.
+    #[serde(rename = "verbose")]
+    Verbose,
+}
+
+/// How to handle a token that matches more than one policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum MatchMode {
+    /// Only the first matching policy is used.
+    #[default]
+    #[serde(rename = "first")]
+    First,
+
+    /// Every matching policy contributes its permissions.
+    #[serde(rename = "union")]
+    Union,
+}
+
+/// Where to record exchanged tokens, for issuers that have `single_use` enabled.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub enum ReplayStoreConfig {
+    #[default]
+    #[serde(rename = "memory")]
+    Memory,
+
+    #[serde(rename = "sqlite")]
+    Sqlite(PathBuf),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("while reading {}", path.display()))?;
+        let mut config: Config = toml::from_str(&contents)
+            .with_context(|| format!("while parsing {}", path.display()))?;
+
+        let mut policies: Vec<&Policy> = config.policies.values().flatten().collect();
+        policies.sort_by_key(|p| p.index);
+        let mut names = HashSet::new();
+        for policy in policies {
+            policy.validate()?;
+            if let Some(name) = &policy.name
+                && !names.insert(name)
+            {
+                anyhow::bail!("multiple policies are named `{name}`");
+            }
+
+            if policy
+                .min_remaining_lifetime
+                .is_some_and(|lifetime| lifetime > policy.duration)
+            {
+                anyhow::bail!(
+                    "{} has a `min_remaining_lifetime` longer than its `duration`",
+                    policy.label()
+                );
+            }
+
+            let allow_insecure_http = config
+                .issuers
+                .get(&policy.issuer)
+                .is_some_and(|i| i.allow_insecure_http);
+            if !allow_insecure_http && !is_https(&policy.issuer)? {
+                anyhow::bail!(
+                    "{} uses issuer `{}` which does not use HTTPS, set `allow_insecure_http = true` in its `issuers` entry to allow this",
+                    policy.label(),
+                    policy.issuer
+                );
+            }
+
+            policy.check_github_numeric_claims();
+            policy.check_audience_claims();
+        }
+
+        for issuer in config.issuers.values() {
+            issuer.validate()?;
+            if !config.policies.contains_key(&issuer.issuer) {
+                tracing::warn!(issuer = issuer.issuer, "issuer is not used by any policy");
+            }
+        }
+        for issuer in config.policies.keys() {
+            if !config.issuers.contains_key(issuer) {
+                config
+                    .issuers
+                    .insert(issuer.clone(), IssuerConfig::new(issuer));
+            }
+        }
+
+        Ok(config)
+    }
 }
 
 // A lot of the code below was taken from attic's own source code with minor changes.
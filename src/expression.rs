@@ -0,0 +1,430 @@
+use crate::verifier::Claims;
+use anyhow::{Context as _, bail};
+use cel::common::ast::operators::{
+    ADD, CONDITIONAL, DIVIDE, EQUALS, GREATER, GREATER_EQUALS, IN, INDEX, LESS, LESS_EQUALS,
+    LOGICAL_AND, LOGICAL_NOT, LOGICAL_OR, MODULO, MULTIPLY, NEGATE, NOT_EQUALS, NOT_STRICTLY_FALSE,
+    SUBSTRACT,
+};
+use cel::common::ast::{EntryExpr, Expr, IdedExpr, LiteralValue};
+use cel::{Context, Program, Value};
+use itertools::Itertools;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::Arc;
+
+/// Limits on the size of an expression. Together with the limit on nested comprehensions, these
+/// bound the cost of evaluating an expression relative to the size of the token.
+const MAX_LENGTH: usize = 4096;
+const MAX_NODES: usize = 512;
+const MAX_DEPTH: usize = 32;
+const MAX_COMPREHENSION_NESTING: usize = 2;
+
+/// The variable through which expressions access the token's claims.
+const CLAIMS_VARIABLE: &str = "claims";
+
+/// A CEL expression over the claims of a token, which must evaluate to a boolean.
+#[derive(Clone, derive_more::Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    source: String,
+    #[debug(skip)]
+    program: Arc<Program>,
+}
+
+impl TryFrom<String> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> anyhow::Result<Expression> {
+        if source.len() > MAX_LENGTH {
+            bail!("expression is longer than {MAX_LENGTH} characters");
+        }
+
+        let program = Program::compile(&source).map_err(|err| anyhow::anyhow!("{err}"))?;
+        let mut checker = Checker::default();
+        let ty = checker.check(program.expression(), 0)?;
+        expect(ty, &[Type::Bool], "the expression")?;
+
+        Ok(Expression {
+            source,
+            program: Arc::new(program),
+        })
+    }
+}
+
+impl Expression {
+    /// Whether the expression constrains the claims, as opposed to possibly being true
+    /// regardless of them.
+    ///
+    /// This is best-effort: it only looks through `&&`, `||`, `!` and `? :`, and treats any other
+    /// part of the expression which refers to the claims as a constraint.
+    pub fn has_constraints(&self) -> bool {
+        !may_be_constant(self.program.expression(), true)
+    }
+
+    pub fn evaluate(&self, claims: &Claims) -> anyhow::Result<bool> {
+        let value = cel::to_value(claims.to_map()).context("could not convert claims")?;
+        let mut context = Context::default();
+        context.add_variable_from_value(CLAIMS_VARIABLE, value);
+        match self.program.execute(&context)? {
+            Value::Bool(result) => Ok(result),
+            value => bail!("expected a boolean, got {:?}", value.type_of()),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`", self.source)
+    }
+}
+
+/// Whether the expression may evaluate to the given value regardless of the claims.
+fn may_be_constant(expr: &IdedExpr, value: bool) -> bool {
+    if let Expr::Call(call) = &expr.expr {
+        match (call.func_name.as_str(), call.args.as_slice()) {
+            (LOGICAL_AND, args) if value => return args.iter().all(|a| may_be_constant(a, value)),
+            (LOGICAL_AND, args) => return args.iter().any(|a| may_be_constant(a, value)),
+            (LOGICAL_OR, args) if value => return args.iter().any(|a| may_be_constant(a, value)),
+            (LOGICAL_OR, args) => return args.iter().all(|a| may_be_constant(a, value)),
+            (LOGICAL_NOT, [arg]) => return may_be_constant(arg, !value),
+            (CONDITIONAL, [_, then, otherwise]) => {
+                return may_be_constant(then, value) || may_be_constant(otherwise, value);
+            }
+            _ => {}
+        }
+    }
+    !expr.references().has_variable(CLAIMS_VARIABLE)
+}
+
+/// The type of a value, as far as it can be known before evaluating the expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    Bool,
+    Int,
+    Uint,
+    Double,
+    String,
+    Bytes,
+    Null,
+    List,
+    Map,
+    /// Any type, such as the value of a claim.
+    Dyn,
+}
+
+impl Type {
+    fn is_number(self) -> bool {
+        matches!(self, Type::Int | Type::Uint | Type::Double)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Bool => "bool",
+            Type::Int => "int",
+            Type::Uint => "uint",
+            Type::Double => "double",
+            Type::String => "string",
+            Type::Bytes => "bytes",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Map => "map",
+            Type::Dyn => "dyn",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Check that a value of the given type is one of the expected types, unless it is dynamic.
+fn expect(ty: Type, expected: &[Type], what: &str) -> anyhow::Result<()> {
+    if ty != Type::Dyn && !expected.contains(&ty) {
+        bail!(
+            "{what} must be {}, but is {ty}",
+            expected.iter().map(Type::to_string).join(" or ")
+        );
+    }
+    Ok(())
+}
+
+/// Check that two values may be compared, which can never succeed for different types other
+/// than numbers.
+fn expect_comparable(a: Type, b: Type, operator: &str) -> anyhow::Result<()> {
+    let comparable = a == b
+        || [a, b].contains(&Type::Dyn)
+        || [a, b].contains(&Type::Null)
+        || (a.is_number() && b.is_number());
+    if !comparable {
+        bail!("cannot compare {a} with {b} using `{operator}`");
+    }
+    Ok(())
+}
+
+/// The type of the result of a function or operator, given the types of its arguments.
+///
+/// Only the standard functions and operators are checked, anything else is assumed to be
+/// dynamically typed.
+fn call_type(name: &str, target: Option<Type>, args: &[Type]) -> anyhow::Result<Type> {
+    use Type::*;
+
+    let operator = name.trim_matches(['_', '@']);
+    let ty = match (name, target, args) {
+        (LOGICAL_AND | LOGICAL_OR, None, args) => {
+            for &arg in args {
+                expect(arg, &[Bool], &format!("operand of `{operator}`"))?;
+            }
+            Bool
+        }
+        (LOGICAL_NOT | NOT_STRICTLY_FALSE, None, &[arg]) => {
+            expect(arg, &[Bool], "operand of `!`")?;
+            Bool
+        }
+        (EQUALS | NOT_EQUALS, None, &[a, b]) => {
+            expect_comparable(a, b, operator)?;
+            Bool
+        }
+        (LESS | LESS_EQUALS | GREATER | GREATER_EQUALS, None, &[a, b]) => {
+            for arg in [a, b] {
+                let ordered = [Bool, Int, Uint, Double, String, Bytes];
+                expect(arg, &ordered, &format!("operand of `{operator}`"))?;
+            }
+            expect_comparable(a, b, operator)?;
+            Bool
+        }
+        (ADD, None, &[a, b]) => {
+            for arg in [a, b] {
+                let addable = [Int, Uint, Double, String, Bytes, List];
+                expect(arg, &addable, "operand of `+`")?;
+            }
+            match (a, b) {
+                (Dyn, ty) | (ty, Dyn) => ty,
+                (a, b) if a == b => a,
+                (a, b) => bail!("cannot add {a} and {b}"),
+            }
+        }
+        (SUBSTRACT | MULTIPLY | DIVIDE | MODULO, None, &[a, b]) => {
+            for arg in [a, b] {
+                expect(
+                    arg,
+                    &[Int, Uint, Double],
+                    &format!("operand of `{operator}`"),
+                )?;
+            }
+            match (a, b) {
+                (Dyn, ty) | (ty, Dyn) => ty,
+                (a, b) if a == b => a,
+                (a, b) => bail!("cannot use `{operator}` on {a} and {b}"),
+            }
+        }
+        (NEGATE, None, &[arg]) => {
+            expect(arg, &[Int, Double], "operand of `-`")?;
+            arg
+        }
+        (CONDITIONAL, None, &[condition, then, otherwise]) => {
+            expect(condition, &[Bool], "condition of `? :`")?;
+            if then == otherwise { then } else { Dyn }
+        }
+        (INDEX, None, &[container, index]) => {
+            expect(container, &[List, Map], "indexed value")?;
+            if container == List {
+                expect(index, &[Int, Uint], "list index")?;
+            }
+            Dyn
+        }
+        (IN, None, &[_, container]) => {
+            expect(container, &[List, Map], "right operand of `in`")?;
+            Bool
+        }
+        ("startsWith" | "endsWith" | "contains" | "matches", Some(target), &[arg]) => {
+            expect(target, &[String], &format!("receiver of `{name}`"))?;
+            expect(arg, &[String], &format!("argument of `{name}`"))?;
+            Bool
+        }
+        ("matches", None, &[value, pattern]) => {
+            expect(value, &[String], "argument of `matches`")?;
+            expect(pattern, &[String], "argument of `matches`")?;
+            Bool
+        }
+        ("size", Some(arg), &[]) | ("size", None, &[arg]) => {
+            expect(arg, &[String, Bytes, List, Map], "argument of `size`")?;
+            Int
+        }
+        ("int", None, &[_]) => Int,
+        ("uint", None, &[_]) => Uint,
+        ("double", None, &[_]) => Double,
+        ("string", None, &[_]) => String,
+        ("bytes", None, &[_]) => Bytes,
+        _ => Dyn,
+    };
+    Ok(ty)
+}
+
+/// Static checks on an expression's syntax tree.
+///
+/// There is no type checker for CEL in Rust, so this infers the types of literals and of the
+/// results of standard functions and operators, treating the claims as dynamically typed. It
+/// also catches references to unknown variables and enforces the size limits.
+#[derive(Default)]
+struct Checker<'a> {
+    nodes: usize,
+    comprehensions: usize,
+    /// Variables bound by the enclosing comprehensions.
+    scope: Vec<&'a str>,
+}
+
+impl<'a> Checker<'a> {
+    fn check(&mut self, expr: &'a IdedExpr, depth: usize) -> anyhow::Result<Type> {
+        self.nodes += 1;
+        if self.nodes > MAX_NODES {
+            bail!("expression has more than {MAX_NODES} nodes");
+        }
+        if depth > MAX_DEPTH {
+            bail!("expression is nested more than {MAX_DEPTH} levels deep");
+        }
+
+        let depth = depth + 1;
+        let ty = match &expr.expr {
+            Expr::Unspecified => Type::Dyn,
+            Expr::Literal(literal) => match literal {
+                LiteralValue::Boolean(_) => Type::Bool,
+                LiteralValue::Bytes(_) => Type::Bytes,
+                LiteralValue::Double(_) => Type::Double,
+                LiteralValue::Int(_) => Type::Int,
+                LiteralValue::Null => Type::Null,
+                LiteralValue::String(_) => Type::String,
+                LiteralValue::UInt(_) => Type::Uint,
+            },
+            Expr::Ident(name) => {
+                // Variables bound by comprehensions shadow the claims.
+                if self.scope.contains(&name.as_str()) {
+                    Type::Dyn
+                } else if name == CLAIMS_VARIABLE {
+                    Type::Map
+                } else {
+                    bail!("undeclared reference to `{name}`");
+                }
+            }
+            Expr::Select(select) => {
+                let operand = self.check(&select.operand, depth)?;
+                expect(
+                    operand,
+                    &[Type::Map],
+                    &format!("value of `.{}`", select.field),
+                )?;
+                if select.test { Type::Bool } else { Type::Dyn }
+            }
+            Expr::Call(call) => {
+                let target = match &call.target {
+                    Some(target) => Some(self.check(target, depth)?),
+                    None => None,
+                };
+                let args = call
+                    .args
+                    .iter()
+                    .map(|arg| self.check(arg, depth))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                call_type(&call.func_name, target, &args)?
+            }
+            Expr::List(list) => {
+                for element in &list.elements {
+                    self.check(element, depth)?;
+                }
+                Type::List
+            }
+            Expr::Map(map) => {
+                for entry in &map.entries {
+                    match &entry.expr {
+                        EntryExpr::MapEntry(entry) => {
+                            self.check(&entry.key, depth)?;
+                            self.check(&entry.value, depth)?;
+                        }
+                        EntryExpr::StructField(_) => bail!("message types are not supported"),
+                    }
+                }
+                Type::Map
+            }
+            Expr::Struct(_) => bail!("message types are not supported"),
+            Expr::Comprehension(comprehension) => {
+                if self.comprehensions >= MAX_COMPREHENSION_NESTING {
+                    bail!(
+                        "expression has more than {MAX_COMPREHENSION_NESTING} nested comprehensions"
+                    );
+                }
+                let range = self.check(&comprehension.iter_range, depth)?;
+                expect(range, &[Type::List, Type::Map], "range of a comprehension")?;
+
+                let scope = self.scope.len();
+                self.check(&comprehension.accu_init, depth)?;
+                self.scope.push(&comprehension.accu_var);
+                self.scope.push(&comprehension.iter_var);
+                if let Some(var) = &comprehension.iter_var2 {
+                    self.scope.push(var);
+                }
+                self.comprehensions += 1;
+                self.check(&comprehension.loop_cond, depth)?;
+                self.check(&comprehension.loop_step, depth)?;
+                self.comprehensions -= 1;
+                self.check(&comprehension.result, depth)?;
+                self.scope.truncate(scope);
+                Type::Dyn
+            }
+        };
+        Ok(ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> anyhow::Result<Expression> {
+        Expression::try_from(source.to_owned())
+    }
+
+    #[test]
+    fn accepts_well_typed_expressions() {
+        for source in [
+            r#"claims.repository_owner_id == "1234" && claims.ref.startsWith("refs/tags/v")"#,
+            r#"has(claims.groups) && claims.groups.exists(g, g == "admins")"#,
+            r#"size(claims.sub) > 3 || claims.run_attempt == 1"#,
+            r#"claims.ref.matches("^refs/tags/v[0-9]+$") ? claims.actor != "bot" : false"#,
+            r#""admins" in claims.groups && claims.groups.all(g, g.endsWith("s"))"#,
+        ] {
+            compile(source).unwrap_or_else(|err| panic!("{source}: {err:#}"));
+        }
+    }
+
+    #[test]
+    fn rejects_ill_typed_expressions() {
+        for source in [
+            r#"claims.ref.startsWith(1)"#,
+            r#"claims.ref == "main" && "yes""#,
+            r#"size(claims.sub) == "3""#,
+            r#"claims.groups.exists(g, g.size())"#,
+            r#""refs/heads/main".foo == 1"#,
+            r#"1 + "a" == claims.x"#,
+            r#"size(claims.sub)"#,
+        ] {
+            assert!(compile(source).is_err(), "{source} should be rejected");
+        }
+    }
+
+    #[test]
+    fn constraints() {
+        for (source, constrained) in [
+            (r#"claims.ref == "main""#, true),
+            (r#"claims.ref == "main" && true"#, true),
+            (r#"has(claims.sub) && false"#, true),
+            (r#"true"#, false),
+            (r#"has(claims.sub) || true"#, false),
+            (r#"!(claims.ref == "main" && false)"#, false),
+            (r#"claims.ref == "main" ? true : 1 == 1"#, false),
+        ] {
+            assert_eq!(
+                compile(source).unwrap().has_constraints(),
+                constrained,
+                "{source}"
+            );
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use crate::Error;
+use crate::config::IssuerConfig;
+use anyhow::Context;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a JWKS is cached for when the issuer does not specify a `max-age`.
+const DEFAULT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Bounds applied to the issuer's `max-age`, to avoid both refetching on every request and holding
+/// on to a key set for so long that rotations go unnoticed.
+const MIN_TTL: Duration = Duration::from_secs(60);
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Minimum time between two fetches of the same issuer's JWKS, regardless of whether the
+/// previous one succeeded.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a key ID that could not be found in the JWKS is remembered for, and how many such
+/// key IDs are remembered per issuer.
+const UNKNOWN_KID_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_UNKNOWN_KIDS: usize = 1024;
+
+#[derive(Debug, serde::Deserialize)]
+struct OpenIdConfig {
+    issuer: String,
+    jwks_uri: String,
+}
+
+struct FetchedJwks {
+    jwks: JwkSet,
+    ttl: Duration,
+}
+
+/// Check that the discovery document was published by the expected issuer, and that its
+/// `jwks_uri` can be trusted to serve that issuer's keys.
+fn validate_discovery(issuer: &IssuerConfig, config: &OpenIdConfig) -> Result<(), Error> {
+    if config.issuer != issuer.issuer {
+        return Err(Error::DiscoveryIssuerMismatch {
+            expected: issuer.issuer.clone(),
+            actual: config.issuer.clone(),
+        });
+    }
+
+    let jwks_uri = reqwest::Url::parse(&config.jwks_uri)
+        .with_context(|| format!("invalid jwks_uri `{}`", config.jwks_uri))?;
+    let issuer_url = reqwest::Url::parse(&issuer.issuer)
+        .with_context(|| format!("invalid issuer URL `{}`", issuer.issuer))?;
+
+    if jwks_uri.scheme() != "https" && !issuer.allow_insecure_http {
+        return Err(Error::InsecureJwksUri {
+            uri: config.jwks_uri.clone(),
+        });
+    }
+
+    if !issuer.allow_cross_origin_jwks_uri && jwks_uri.origin() != issuer_url.origin() {
+        return Err(Error::CrossOriginJwksUri {
+            uri: config.jwks_uri.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+async fn fetch_jwks(client: &reqwest::Client, issuer: &IssuerConfig) -> Result<FetchedJwks, Error> {
+    let jwks_uri = match &issuer.jwks_uri {
+        Some(uri) => uri.clone(),
+        None => {
+            let url = issuer.discovery_url();
+            let config: OpenIdConfig = async {
+                client
+                    .get(&url)
+                    .timeout(issuer.http_timeout)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+            }
+            .await
+            .with_context(|| format!("while fetching discovery document from {url}"))?;
+            validate_discovery(issuer, &config)?;
+            config.jwks_uri
+        }
+    };
+
+    let response = async {
+        client
+            .get(&jwks_uri)
+            .timeout(issuer.http_timeout)
+            .send()
+            .await?
+            .error_for_status()
+    }
+    .await
+    .with_context(|| format!("while fetching JWKS from {jwks_uri}"))?;
+    let ttl = cache_ttl(response.headers());
+    let jwks: JwkSet = response
+        .json()
+        .await
+        .with_context(|| format!("while reading JWKS from {jwks_uri}"))?;
+    Ok(FetchedJwks { jwks, ttl })
+}
+
+/// Compute how long a response may be cached for, based on its `Cache-Control` header.
+fn cache_ttl(headers: &reqwest::header::HeaderMap) -> Duration {
+    let max_age = headers
+        .get_all(reqwest::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| {
+            let (name, value) = directive.trim().split_once('=')?;
+            if name.eq_ignore_ascii_case("max-age") {
+                value.trim_matches('"').parse::<u64>().ok()
+            } else {
+                None
+            }
+        });
+
+    match max_age {
+        Some(secs) => Duration::from_secs(secs).clamp(MIN_TTL, MAX_TTL),
+        None => DEFAULT_TTL,
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    jwks: Arc<JwkSet>,
+    fetched_at: Instant,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct IssuerState {
+    entry: Option<Entry>,
+    last_attempt: Option<Instant>,
+    last_error: Option<String>,
+    unknown_kids: HashMap<String, Instant>,
+}
+
+impl IssuerState {
+    fn can_refresh(&self, now: Instant) -> bool {
+        self.last_attempt
+            .is_none_or(|t| now >= t + MIN_REFRESH_INTERVAL)
+    }
+
+    fn is_unknown_kid(&self, kid: &str, now: Instant) -> bool {
+        self.unknown_kids
+            .get(kid)
+            .is_some_and(|&t| now < t + UNKNOWN_KID_TTL)
+    }
+
+    fn remember_unknown_kid(&mut self, kid: &str, now: Instant) {
+        if self.unknown_kids.len() >= MAX_UNKNOWN_KIDS {
+            self.unknown_kids
+                .retain(|_, &mut t| now < t + UNKNOWN_KID_TTL);
+        }
+        // If the negative cache is still full, drop the entry. The refresh rate limit still
+        // applies so this does not let the issuer get hammered.
+        if self.unknown_kids.len() < MAX_UNKNOWN_KIDS {
+            self.unknown_kids.insert(kid.to_owned(), now);
+        }
+    }
+
+    /// Find the keys with the given key ID in the cached set.
+    ///
+    /// A missing key ID is only remembered as unknown if the set was fetched after the lookup
+    /// was requested, otherwise the key may simply have been added since the last fetch.
+    fn find(
+        &mut self,
+        issuer: &str,
+        kid: Option<&str>,
+        requested_at: Instant,
+    ) -> Result<Vec<Jwk>, Error> {
+        let Some(entry) = &self.entry else {
+            return Err(Error::KeysUnavailable {
+                issuer: issuer.to_owned(),
+                reason: self.last_error.clone().unwrap_or_default(),
+            });
+        };
+        let keys = matching_keys(&entry.jwks, kid);
+        if keys.is_empty() {
+            if let Some(kid) = kid
+                && entry.fetched_at >= requested_at
+            {
+                self.remember_unknown_kid(kid, Instant::now());
+            }
+            return Err(Error::Other(anyhow::anyhow!("Unknown key")));
+        }
+        Ok(keys)
+    }
+}
+
+/// Find the keys in the set with the given key ID, or all of them if no key ID is given.
+pub fn matching_keys(jwks: &JwkSet, kid: Option<&str>) -> Vec<Jwk> {
+    jwks.keys
+        .iter()
+        .filter(|key| kid.is_none() || key.common.key_id.as_deref() == kid)
+        .cloned()
+        .collect()
+}
+
+#[derive(Default)]
+struct IssuerCache {
+    state: Mutex<IssuerState>,
+
+    /// Held while a fetch is in flight, so that concurrent lookups wait for its result instead
+    /// of starting their own.
+    refresh: tokio::sync::Mutex<()>,
+}
+
+/// An in-memory cache of each issuer's JSON Web Key Set.
+///
+/// Key sets are refreshed when they expire or when a token refers to a key ID that is not in
+/// the cached set. If a refresh fails, the last successfully fetched set keeps being used.
+///
+/// Concurrent lookups for the same issuer share a single fetch, refreshes are rate limited and
+/// key IDs that could not be found are remembered for a while, such that tokens with made up
+/// key IDs cannot be used to flood the issuer with requests.
+#[derive(Default)]
+pub struct JwksCache {
+    client: reqwest::Client,
+    issuers: Mutex<HashMap<String, Arc<IssuerCache>>>,
+}
+
+impl JwksCache {
+    /// Find the issuer's keys with the given key ID, or all of its keys if no key ID is given.
+    pub async fn find(&self, issuer: &IssuerConfig, kid: Option<&str>) -> Result<Vec<Jwk>, Error> {
+        let cache = self
+            .issuers
+            .lock()
+            .unwrap()
+            .entry(issuer.issuer.clone())
+            .or_default()
+            .clone();
+
+        let requested_at = Instant::now();
+        {
+            let mut state = cache.state.lock().unwrap();
+            let can_refresh = state.can_refresh(requested_at);
+            if let Some(entry) = &state.entry {
+                let found = !matching_keys(&entry.jwks, kid).is_empty();
+                let fresh = entry.expires_at > requested_at;
+                let unknown = kid.is_some_and(|kid| state.is_unknown_kid(kid, requested_at));
+                if (found && fresh) || !can_refresh || unknown {
+                    return state.find(&issuer.issuer, kid, requested_at);
+                }
+            } else if !can_refresh {
+                return state.find(&issuer.issuer, kid, requested_at);
+            }
+        }
+
+        let _guard = cache.refresh.lock().await;
+
+        // Another lookup may have refreshed the keys while we were waiting for the lock, in which
+        // case we use its result rather than fetching them again.
+        if cache
+            .state
+            .lock()
+            .unwrap()
+            .last_attempt
+            .is_none_or(|t| t < requested_at)
+        {
+            let result = fetch_jwks(&self.client, issuer).await;
+
+            let mut state = cache.state.lock().unwrap();
+            let now = Instant::now();
+            state.last_attempt = Some(now);
+            match result {
+                Ok(fetched) => {
+                    state.entry = Some(Entry {
+                        jwks: Arc::new(fetched.jwks),
+                        fetched_at: now,
+                        expires_at: now + fetched.ttl,
+                    });
+                    state.last_error = None;
+                    state.unknown_kids.clear();
+                }
+                Err(err) => {
+                    state.last_error = Some(format!("{err:#}"));
+                    if state.entry.is_none() {
+                        return Err(err);
+                    }
+                    tracing::warn!(
+                        issuer = issuer.issuer,
+                        "could not refresh JWKS, using previously fetched keys: {err:#}"
+                    );
+                }
+            }
+        }
+
+        cache
+            .state
+            .lock()
+            .unwrap()
+            .find(&issuer.issuer, kid, requested_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(fetched_at: Instant) -> IssuerState {
+        IssuerState {
+            entry: Some(Entry {
+                jwks: Arc::new(JwkSet { keys: Vec::new() }),
+                fetched_at,
+                expires_at: fetched_at + DEFAULT_TTL,
+            }),
+            ..IssuerState::default()
+        }
+    }
+
+    #[test]
+    fn remembers_kid_missing_from_fresh_fetch() {
+        let requested_at = Instant::now();
+        let mut state = state(requested_at);
+        assert!(state.find("issuer", Some("new"), requested_at).is_err());
+        assert!(state.is_unknown_kid("new", Instant::now()));
+    }
+
+    #[test]
+    fn does_not_remember_kid_missing_from_earlier_fetch() {
+        let fetched_at = Instant::now();
+        let mut state = state(fetched_at);
+        let requested_at = fetched_at + Duration::from_secs(1);
+        assert!(state.find("issuer", Some("new"), requested_at).is_err());
+        assert!(!state.is_unknown_kid("new", requested_at));
+    }
+}
@@ -1,10 +1,20 @@
+use crate::api::TokenResponse;
 use crate::config::Config;
+use crate::verifier::Verifier;
 use clap::{Parser, Subcommand};
+use itertools::Itertools;
 use std::path::PathBuf;
 
 mod api;
+mod claim_path;
 mod client;
+mod condition;
 mod config;
+mod expression;
+mod jwks;
+mod matcher;
+mod replay;
+mod template;
 mod token;
 mod verifier;
 
@@ -16,7 +26,7 @@ enum Command {
         #[arg(long)]
         config: PathBuf,
     },
-    API {
+    Api {
         #[arg(long)]
         config: PathBuf,
     },
@@ -38,19 +48,102 @@ pub enum Error {
     #[error("invalid or missing claim `{claim}`")]
     InvalidClaim { claim: String },
 
+    #[error("claim `{claim}` has a value which cannot be substituted into `{template}`")]
+    InvalidTemplateValue { claim: String, template: String },
+
+    #[error("claim `{claim}` is missing, so a negated condition on it cannot be checked")]
+    UndecidableClaim { claim: String },
+
+    #[error("claim `{claim}` does not match `{matcher}`")]
+    ClaimMismatch { claim: String, matcher: String },
+
+    #[error("condition not met: {0}")]
+    ConditionNotMet(String),
+
+    #[error("failed to evaluate expression {expression}: {reason}")]
+    ExpressionFailed { expression: String, reason: String },
+
     #[error("policy has empty required claims")]
     EmptyPolicyClaims,
 
-    #[error("token did not match any registered policy {0:?}")]
-    NoValidPolicy(Vec<Error>),
+    #[error("discovery document has issuer `{actual}`, expected `{expected}`")]
+    DiscoveryIssuerMismatch { expected: String, actual: String },
+
+    #[error("JWKS URI `{uri}` does not use HTTPS")]
+    InsecureJwksUri { uri: String },
+
+    #[error("JWKS URI `{uri}` is not on the same origin as its issuer")]
+    CrossOriginJwksUri { uri: String },
+
+    #[error("keys for issuer `{issuer}` are not available: {reason}")]
+    KeysUnavailable { issuer: String, reason: String },
+
+    #[error(
+        "issued token would only be valid for {remaining}s, but at least {required}s are required. Obtain a fresh ID token and try again"
+    )]
+    TokenLifetimeTooShort { remaining: u64, required: u64 },
+
+    #[error("token has already been exchanged")]
+    TokenReplayed,
+
+    #[error("token was denied by {policy}")]
+    Denied { policy: String },
+
+    #[error("token did not match any registered policy: {}", .0.iter().join("; "))]
+    NoValidPolicy(Vec<PolicyFailure>),
 
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
-async fn exchange(token: &str, config: &Config) -> Result<String, Error> {
-    let (claims, policy) = crate::verifier::verify(&token, &config).await?;
-    token::issue(&claims, policy, config)
+impl Error {
+    /// Whether the error means that the token plainly does not match a policy, as opposed to the
+    /// policy not being possible to check against the token.
+    pub fn is_mismatch(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidClaim { .. } | Error::ClaimMismatch { .. } | Error::ConditionNotMet(_)
+        )
+    }
+
+    /// Whether the error reveals something about the configured policies, such that it should
+    /// not be sent to clients unless verbose error responses are enabled.
+    pub fn describes_policies(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidClaim { .. }
+                | Error::InvalidTemplateValue { .. }
+                | Error::UndecidableClaim { .. }
+                | Error::ClaimMismatch { .. }
+                | Error::ConditionNotMet(_)
+                | Error::ExpressionFailed { .. }
+                | Error::EmptyPolicyClaims
+                | Error::Denied { .. }
+                | Error::NoValidPolicy(_)
+        )
+    }
+}
+
+/// The reason why a token did not match a policy.
+#[derive(thiserror::Error, Debug)]
+#[error("{policy}: {error}")]
+pub struct PolicyFailure {
+    pub policy: String,
+    pub error: Error,
+}
+
+async fn exchange(
+    token: &str,
+    config: &Config,
+    verifier: &Verifier,
+) -> Result<TokenResponse, Error> {
+    let (claims, policies) = verifier.verify(token, config).await?;
+    let issued = token::issue(&claims, &policies, config)?;
+    verifier.check_replay(token, &claims, config).await?;
+    Ok(TokenResponse {
+        token: issued,
+        policies: policies.iter().map(|p| p.identifier()).collect(),
+    })
 }
 
 #[tokio::main]
@@ -60,17 +153,12 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     match cli.command {
         Command::Exchange { token, config } => {
-            let config = {
-                let contents = std::fs::read_to_string(&config)?;
-                toml::from_str::<Config>(&contents)?
-            };
-            println!("{}", exchange(&token, &config).await?);
+            let config = Config::load(&config)?;
+            let verifier = Verifier::new(&config)?;
+            println!("{}", exchange(&token, &config, &verifier).await?.token);
         }
-        Command::API { config } => {
-            let config = {
-                let contents = std::fs::read_to_string(&config)?;
-                toml::from_str::<Config>(&contents)?
-            };
+        Command::Api { config } => {
+            let config = Config::load(&config)?;
             api::run(config).await?;
         }
         Command::Login { url, token } => {
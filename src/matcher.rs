@@ -0,0 +1,273 @@
+use crate::claim_path::ClaimPath;
+use globset::{GlobBuilder, GlobMatcher};
+use itertools::Itertools;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A constraint on the value of a claim.
+///
+/// In the configuration, tables are interpreted as matchers (eg. `{ glob = "refs/heads/*" }`)
+/// and any other value must be equal to the claim. Table-valued claims can be compared using
+/// the explicit `{ equals = ... }` form.
+#[derive(Clone, Debug)]
+pub enum ClaimMatcher {
+    Equals(serde_json::Value),
+    Number(serde_json::Number),
+    Glob(Glob),
+    Regex(Regex),
+    OneOf(Vec<ClaimMatcher>),
+    Not(Box<ClaimMatcher>),
+    Contains(Box<ClaimMatcher>),
+    ContainsAll(Vec<ClaimMatcher>),
+    ContainsAny(Vec<ClaimMatcher>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+enum MatcherConfig {
+    Equals(serde_json::Value),
+    Number(serde_json::Number),
+    Glob(String),
+    Regex(String),
+    OneOf(Vec<serde_json::Value>),
+    Not(serde_json::Value),
+    Contains(serde_json::Value),
+    ContainsAll(Vec<serde_json::Value>),
+    ContainsAny(Vec<serde_json::Value>),
+}
+
+/// A shell-style pattern. `*` and `?` do not match `/`, whereas `**` does.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    matcher: GlobMatcher,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> anyhow::Result<Glob> {
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .backslash_escape(true)
+            .build()?
+            .compile_matcher();
+        Ok(Glob { matcher })
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.matcher.is_match(value)
+    }
+}
+
+/// A regular expression, which must match the entire value.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    pattern: String,
+    regex: regex::Regex,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> anyhow::Result<Regex> {
+        // Parse the pattern on its own first, such that errors refer to what was written.
+        regex::Regex::new(pattern)?;
+
+        // The pattern is anchored on both ends, regardless of whether it already is, such that
+        // a forgotten `^` or `$` cannot cause unexpected values to be accepted.
+        let regex = regex::Regex::new(&format!("^(?:{pattern})$"))?;
+        Ok(Regex {
+            pattern: pattern.to_owned(),
+            regex,
+        })
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+impl TryFrom<serde_json::Value> for ClaimMatcher {
+    type Error = anyhow::Error;
+
+    fn try_from(value: serde_json::Value) -> anyhow::Result<ClaimMatcher> {
+        if !value.is_object() {
+            return Ok(ClaimMatcher::Equals(value));
+        }
+
+        match MatcherConfig::deserialize(value)? {
+            MatcherConfig::Equals(value) => Ok(ClaimMatcher::Equals(value)),
+            MatcherConfig::Number(number) => Ok(ClaimMatcher::Number(number)),
+            MatcherConfig::Glob(pattern) => Ok(ClaimMatcher::Glob(Glob::new(&pattern)?)),
+            MatcherConfig::Regex(pattern) => Ok(ClaimMatcher::Regex(Regex::new(&pattern)?)),
+            MatcherConfig::OneOf(values) => Ok(ClaimMatcher::OneOf(try_from_all(values)?)),
+            MatcherConfig::Not(value) => Ok(ClaimMatcher::Not(Box::new(value.try_into()?))),
+            MatcherConfig::Contains(value) => {
+                Ok(ClaimMatcher::Contains(Box::new(value.try_into()?)))
+            }
+            MatcherConfig::ContainsAll(values) => {
+                Ok(ClaimMatcher::ContainsAll(try_from_all(values)?))
+            }
+            MatcherConfig::ContainsAny(values) => {
+                Ok(ClaimMatcher::ContainsAny(try_from_all(values)?))
+            }
+        }
+    }
+}
+
+fn try_from_all(values: Vec<serde_json::Value>) -> anyhow::Result<Vec<ClaimMatcher>> {
+    values.into_iter().map(ClaimMatcher::try_from).collect()
+}
+
+pub fn deserialize_claim_matchers<'de, D>(
+    d: D,
+) -> Result<HashMap<ClaimPath, ClaimMatcher>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let claims: HashMap<String, serde_json::Value> = Deserialize::deserialize(d)?;
+    claims
+        .into_iter()
+        .map(|(claim, value)| {
+            let path = ClaimPath::parse(&claim).map_err(|err| Error::custom(format!("{err:#}")))?;
+            match ClaimMatcher::try_from(value) {
+                Ok(matcher) => Ok((path, matcher)),
+                Err(err) => Err(Error::custom(format!("claim `{claim}`: {err:#}"))),
+            }
+        })
+        .collect()
+}
+
+impl ClaimMatcher {
+    pub fn matches(&self, value: &serde_json::Value) -> bool {
+        match self {
+            ClaimMatcher::Equals(expected) => value == expected,
+            ClaimMatcher::Number(expected) => {
+                parse_number(value).is_some_and(|actual| numbers_equal(&actual, expected))
+            }
+            ClaimMatcher::Glob(glob) => value.as_str().is_some_and(|s| glob.is_match(s)),
+            ClaimMatcher::Regex(regex) => value.as_str().is_some_and(|s| regex.is_match(s)),
+            ClaimMatcher::OneOf(matchers) => matchers.iter().any(|m| m.matches(value)),
+            ClaimMatcher::Not(matcher) => !matcher.matches(value),
+            ClaimMatcher::Contains(matcher) => value
+                .as_array()
+                .is_some_and(|items| items.iter().any(|item| matcher.matches(item))),
+            ClaimMatcher::ContainsAll(matchers) => value.as_array().is_some_and(|items| {
+                matchers
+                    .iter()
+                    .all(|matcher| items.iter().any(|item| matcher.matches(item)))
+            }),
+            ClaimMatcher::ContainsAny(matchers) => value.as_array().is_some_and(|items| {
+                matchers
+                    .iter()
+                    .any(|matcher| items.iter().any(|item| matcher.matches(item)))
+            }),
+        }
+    }
+}
+
+impl ClaimMatcher {
+    /// Whether the matcher compares the claim for equality with a number literal. Claims which
+    /// contain numbers encoded as strings would never match such a comparison.
+    pub fn compares_number_literal(&self) -> bool {
+        match self {
+            ClaimMatcher::Equals(value) => value.is_number(),
+            ClaimMatcher::OneOf(matchers) => matchers.iter().any(|m| m.compares_number_literal()),
+            ClaimMatcher::Not(matcher) => matcher.compares_number_literal(),
+            _ => false,
+        }
+    }
+
+    /// Whether the matcher negates another one, such that it would match a missing claim if it
+    /// were applied to it.
+    pub fn has_negation(&self) -> bool {
+        match self {
+            ClaimMatcher::Not(_) => true,
+            ClaimMatcher::OneOf(matchers)
+            | ClaimMatcher::ContainsAll(matchers)
+            | ClaimMatcher::ContainsAny(matchers) => matchers.iter().any(|m| m.has_negation()),
+            ClaimMatcher::Contains(matcher) => matcher.has_negation(),
+            _ => false,
+        }
+    }
+
+    /// Whether the matcher compares the claim for equality with a value other than an array.
+    /// Claims which are always arrays would never match such a comparison.
+    pub fn compares_non_array_literal(&self) -> bool {
+        match self {
+            ClaimMatcher::Equals(value) => !value.is_array(),
+            ClaimMatcher::OneOf(matchers) => {
+                matchers.iter().any(|m| m.compares_non_array_literal())
+            }
+            ClaimMatcher::Not(matcher) => matcher.compares_non_array_literal(),
+            _ => false,
+        }
+    }
+}
+
+/// Get the numeric value of a claim, which may be either a number or a string containing one.
+fn parse_number(value: &serde_json::Value) -> Option<serde_json::Number> {
+    match value {
+        serde_json::Value::Number(number) => Some(number.clone()),
+        // Only accept strings that are exactly a JSON number, without any surrounding whitespace
+        // or alternative spellings.
+        serde_json::Value::String(s) if s.trim() == s => serde_json::from_str(s).ok(),
+        _ => None,
+    }
+}
+
+fn numbers_equal(a: &serde_json::Number, b: &serde_json::Number) -> bool {
+    fn as_integer(n: &serde_json::Number) -> Option<i128> {
+        n.as_i64()
+            .map(i128::from)
+            .or_else(|| n.as_u64().map(i128::from))
+    }
+
+    match (as_integer(a), as_integer(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.as_f64() == b.as_f64(),
+    }
+}
+
+impl fmt::Display for ClaimMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaimMatcher::Equals(value) => write!(f, "equals {value}"),
+            ClaimMatcher::Number(number) => write!(f, "number {number}"),
+            ClaimMatcher::Glob(glob) => write!(f, "glob {:?}", glob.matcher.glob().glob()),
+            ClaimMatcher::Regex(regex) => write!(f, "regex {:?}", regex.pattern),
+            ClaimMatcher::OneOf(matchers) => {
+                write!(f, "one_of [{}]", matchers.iter().format(", "))
+            }
+            ClaimMatcher::Not(matcher) => write!(f, "not ({matcher})"),
+            ClaimMatcher::Contains(matcher) => write!(f, "contains ({matcher})"),
+            ClaimMatcher::ContainsAll(matchers) => {
+                write!(f, "contains_all [{}]", matchers.iter().format(", "))
+            }
+            ClaimMatcher::ContainsAny(matchers) => {
+                write!(f, "contains_any [{}]", matchers.iter().format(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regex_is_anchored() {
+        let regex = Regex::new("foo|bar").unwrap();
+        assert!(regex.is_match("foo"));
+        assert!(regex.is_match("bar"));
+        assert!(!regex.is_match("xfoo"));
+        assert!(!regex.is_match("barx"));
+        assert!(!regex.is_match("foo\nbar"));
+    }
+
+    #[test]
+    fn regex_cannot_escape_anchors() {
+        assert!(Regex::new("a)|(b").is_err());
+        assert!(Regex::new("a)|(?:b").is_err());
+    }
+}
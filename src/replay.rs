@@ -0,0 +1,73 @@
+use crate::config::ReplayStoreConfig;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Keeps track of the ID tokens that have already been exchanged, until they expire.
+pub enum ReplayStore {
+    Memory(Mutex<HashMap<String, u64>>),
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+}
+
+fn open_sqlite(path: &Path) -> anyhow::Result<rusqlite::Connection> {
+    let connection = rusqlite::Connection::open(path)?;
+    connection.execute_batch(
+        "PRAGMA journal_mode = WAL;
+         CREATE TABLE IF NOT EXISTS used_tokens (
+             id TEXT PRIMARY KEY NOT NULL,
+             expires_at INTEGER NOT NULL
+         );",
+    )?;
+    Ok(connection)
+}
+
+impl ReplayStore {
+    pub fn open(config: &ReplayStoreConfig) -> anyhow::Result<ReplayStore> {
+        match config {
+            ReplayStoreConfig::Memory => Ok(ReplayStore::Memory(Mutex::default())),
+            ReplayStoreConfig::Sqlite(path) => {
+                let connection = open_sqlite(path).map_err(|e| {
+                    e.context(format!("while opening replay store {}", path.display()))
+                })?;
+                Ok(ReplayStore::Sqlite(Arc::new(Mutex::new(connection))))
+            }
+        }
+    }
+
+    /// Record a token ID as used until the given expiry time.
+    ///
+    /// Returns false if the ID had already been recorded and has not expired yet.
+    pub async fn insert(&self, id: String, expires_at: u64) -> anyhow::Result<bool> {
+        let now = jsonwebtoken::get_current_timestamp();
+        match self {
+            ReplayStore::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                entries.retain(|_, &mut t| t >= now);
+                match entries.entry(id) {
+                    Entry::Occupied(_) => Ok(false),
+                    Entry::Vacant(entry) => {
+                        entry.insert(expires_at);
+                        Ok(true)
+                    }
+                }
+            }
+            ReplayStore::Sqlite(connection) => {
+                let connection = connection.clone();
+                tokio::task::spawn_blocking(move || {
+                    let connection = connection.lock().unwrap();
+                    connection.execute(
+                        "DELETE FROM used_tokens WHERE expires_at < ?1",
+                        (now as i64,),
+                    )?;
+                    let inserted = connection.execute(
+                        "INSERT OR IGNORE INTO used_tokens (id, expires_at) VALUES (?1, ?2)",
+                        (&id, i64::try_from(expires_at).unwrap_or(i64::MAX)),
+                    )?;
+                    Ok(inserted == 1)
+                })
+                .await?
+            }
+        }
+    }
+}
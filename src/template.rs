@@ -0,0 +1,157 @@
+use crate::Error;
+use crate::claim_path::ClaimPath;
+use crate::verifier::Claims;
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use std::fmt;
+
+/// A string containing references to claims, eg. `gh-{repository_owner_id}`.
+///
+/// References are written as claim paths surrounded by braces. Braces cannot otherwise appear in
+/// a template.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Part {
+    Literal(String),
+    Claim(ClaimPath),
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> anyhow::Result<Template> {
+        let mut parts = Vec::new();
+        let mut rest = source.as_str();
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(start) if rest[start..].starts_with('{') => {
+                    if start > 0 {
+                        parts.push(Part::Literal(rest[..start].to_owned()));
+                    }
+                    let end = rest[start..]
+                        .find('}')
+                        .map(|end| start + end)
+                        .with_context(|| format!("unclosed `{{` in template `{source}`"))?;
+                    let path = &rest[start + 1..end];
+                    if path.contains('{') {
+                        bail!("nested `{{` in template `{source}`");
+                    }
+                    parts.push(Part::Claim(
+                        ClaimPath::parse(path)
+                            .with_context(|| format!("in template `{source}`"))?,
+                    ));
+                    rest = &rest[end + 1..];
+                }
+                Some(_) => bail!("unmatched `}}` in template `{source}`"),
+                None => {
+                    parts.push(Part::Literal(rest.to_owned()));
+                    rest = "";
+                }
+            }
+        }
+        Ok(Template { source, parts })
+    }
+}
+
+impl Template {
+    /// Substitute the claims referenced by the template.
+    ///
+    /// Claims must be strings or integers, and each substituted value must satisfy `is_allowed`,
+    /// such that a claim cannot inject characters with a special meaning in the result.
+    pub fn render(&self, claims: &Claims, is_allowed: fn(&str) -> bool) -> Result<String, Error> {
+        let mut result = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => result.push_str(literal),
+                Part::Claim(path) => {
+                    let value = claims.lookup(path).ok_or_else(|| Error::InvalidClaim {
+                        claim: path.to_string(),
+                    })?;
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+                        _ => String::new(),
+                    };
+                    if value.is_empty() || !is_allowed(&value) {
+                        return Err(Error::InvalidTemplateValue {
+                            claim: path.to_string(),
+                            template: self.source.clone(),
+                        });
+                    }
+                    result.push_str(&value);
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn claims(value: serde_json::Value) -> Claims {
+        let mut value = value;
+        value["exp"] = json!(0);
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn any(_: &str) -> bool {
+        true
+    }
+
+    #[test]
+    fn parses_templates() {
+        for source in ["plain", "gh-{repository_owner_id}", "{a}-{b.c}", "{/a~1b}x"] {
+            Template::try_from(source.to_owned()).unwrap();
+        }
+        for source in ["{", "a}", "{a", "{{a}}", "{}", "{a..b}"] {
+            assert!(
+                Template::try_from(source.to_owned()).is_err(),
+                "{source:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn renders_strings_and_integers() {
+        let template = Template::try_from("{owner}-{id}-{nested.name}".to_owned()).unwrap();
+        let claims = claims(json!({ "owner": "me", "id": 1234, "nested": { "name": "x" } }));
+        assert_eq!(template.render(&claims, any).unwrap(), "me-1234-x");
+    }
+
+    #[test]
+    fn rejects_other_values() {
+        let template = Template::try_from("cache-{value}".to_owned()).unwrap();
+        for value in [
+            json!(""),
+            json!(1.5),
+            json!(true),
+            json!(null),
+            json!(["a"]),
+        ] {
+            let claims = claims(json!({ "value": value }));
+            assert!(matches!(
+                template.render(&claims, any),
+                Err(Error::InvalidTemplateValue { .. })
+            ));
+        }
+        assert!(matches!(
+            template.render(&claims(json!({})), any),
+            Err(Error::InvalidClaim { .. })
+        ));
+    }
+}
@@ -1,12 +1,12 @@
 use crate::Error;
-use crate::config::{Config, Policy};
+use crate::config::{Config, Permissions, Policy};
 use crate::verifier::Claims;
 use serde::Serialize;
 use serde_with::{BoolFromInt, serde_as};
 use std::collections::{HashMap, HashSet};
 
 fn is_false(v: &bool) -> bool {
-    *v == false
+    !*v
 }
 
 #[serde_as]
@@ -66,20 +66,73 @@ struct AtticTokenBody {
     iat: u64,
 }
 
-pub fn issue(claims: &Claims, policy: &Policy, config: &Config) -> Result<String, Error> {
-    let iat = jsonwebtoken::get_current_timestamp();
-    let exp = if policy.allow_extending_token_lifespan {
+/// Whether a claim value can be substituted into a cache name.
+///
+/// This only allows the characters attic accepts in cache names, which notably excludes the `*`
+/// used by attic for patterns. A claim value therefore cannot be used to grant access to more
+/// than one cache.
+fn is_cache_name_component(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Whether a claim value can be substituted into the subject of the issued token. Control
+/// characters are rejected, such that a claim cannot forge entries in attic's logs.
+fn is_subject_component(value: &str) -> bool {
+    !value.chars().any(char::is_control)
+}
+
+/// Compute the expiry of a token issued under the given policy.
+fn expiry(claims: &Claims, policy: &Policy, iat: u64) -> u64 {
+    if policy.allow_extending_token_lifespan {
         iat + policy.duration.as_secs()
     } else {
         std::cmp::min(claims.exp, iat + policy.duration.as_secs())
-    };
+    }
+}
 
-    let permissions = policy
-        .permissions
+/// Issue an attic token granting the permissions of all the given policies.
+///
+/// The token expires as soon as any of the policies would have it expire, and must satisfy the
+/// largest `min_remaining_lifetime` among them.
+pub fn issue(claims: &Claims, policies: &[&Policy], config: &Config) -> Result<String, Error> {
+    let iat = jsonwebtoken::get_current_timestamp();
+    let exp = policies
         .iter()
+        .map(|policy| expiry(claims, policy, iat))
+        .min()
+        .unwrap_or(iat);
+
+    if let Some(min_remaining_lifetime) = policies
+        .iter()
+        .filter_map(|p| p.min_remaining_lifetime)
+        .max()
+    {
+        let remaining = exp.saturating_sub(iat);
+        if remaining < min_remaining_lifetime.as_secs() {
+            return Err(Error::TokenLifetimeTooShort {
+                remaining,
+                required: min_remaining_lifetime.as_secs(),
+            });
+        }
+    }
+
+    let mut permissions: HashMap<String, Permissions> = HashMap::new();
+    for (cache, granted) in policies.iter().flat_map(|p| &p.permissions) {
+        let cache = cache.render(claims, is_cache_name_component)?;
+        // Different templates or policies may produce the same cache name, in which case all of
+        // their permissions are granted.
+        let merged = match permissions.get(&cache) {
+            Some(existing) => existing.union(granted),
+            None => granted.clone(),
+        };
+        permissions.insert(cache, merged);
+    }
+
+    let permissions = permissions
+        .into_iter()
         .map(|(k, v)| {
             (
-                k.clone(),
+                k,
                 AtticCachePermissions {
                     pull: v.pull,
                     push: v.push,
@@ -93,8 +146,13 @@ pub fn issue(claims: &Claims, policy: &Policy, config: &Config) -> Result<String
         })
         .collect();
 
+    let sub = match policies.iter().find_map(|p| p.subject.as_ref()) {
+        Some(subject) => Some(subject.render(claims, is_subject_component)?),
+        None => claims.sub.clone(),
+    };
+
     let body = AtticTokenBody {
-        sub: claims.sub.clone(),
+        sub,
         exp,
         iat,
         iss: config.jwt.token_bound_issuer.clone(),
@@ -113,3 +171,50 @@ pub fn issue(claims: &Claims, policy: &Policy, config: &Config) -> Result<String
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::Template;
+    use serde_json::json;
+
+    fn render(template: &str, value: &str, is_allowed: fn(&str) -> bool) -> Result<String, Error> {
+        let claims: Claims = serde_json::from_value(json!({ "exp": 0, "value": value })).unwrap();
+        Template::try_from(template.to_owned())
+            .unwrap()
+            .render(&claims, is_allowed)
+    }
+
+    #[test]
+    fn cache_names_reject_special_characters() {
+        assert_eq!(
+            render("gh-{value}", "owner-repo-1", is_cache_name_component).unwrap(),
+            "gh-owner-repo-1"
+        );
+        for value in ["*", "a*", "a/b", "../a", "a.b", "a b", "a\n", "é"] {
+            assert!(
+                matches!(
+                    render("gh-{value}", value, is_cache_name_component),
+                    Err(Error::InvalidTemplateValue { .. })
+                ),
+                "{value:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn subjects_reject_control_characters() {
+        assert_eq!(
+            render(
+                "{value}",
+                "repo:owner/repo:ref:refs/heads/main",
+                is_subject_component
+            )
+            .unwrap(),
+            "repo:owner/repo:ref:refs/heads/main"
+        );
+        for value in ["a\nforged log line", "a\r", "\u{1b}[31m"] {
+            assert!(render("{value}", value, is_subject_component).is_err());
+        }
+    }
+}
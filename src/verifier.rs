@@ -1,12 +1,22 @@
-use crate::Error;
-use crate::config::{Config, Policy};
+use crate::claim_path::ClaimPath;
+use crate::condition::Condition;
+use crate::config::{Config, Effect, IssuerConfig, IssuerKeys, MatchMode, Policy, PublicKey};
+use crate::expression::Expression;
+use crate::jwks::{JwksCache, matching_keys};
+use crate::matcher::ClaimMatcher;
+use crate::replay::ReplayStore;
+use crate::{Error, PolicyFailure};
 use anyhow::Context;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD};
+use itertools::Itertools;
 use jsonwebtoken::Algorithm;
 use jsonwebtoken::DecodingKey;
 use jsonwebtoken::TokenData;
 use jsonwebtoken::Validation;
-use jsonwebtoken::jwk::{Jwk, JwkSet, KeyAlgorithm};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, KeyAlgorithm};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -25,19 +35,63 @@ pub struct Claims {
     // to make this an Option.
     pub exp: u64,
 
+    /// The token's audiences. A single audience is normalized into a one-element list.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_audience")]
+    pub aud: Vec<String>,
+
     #[serde(flatten)]
     other: HashMap<String, serde_json::Value>,
 }
 
+fn deserialize_audience<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Audience {
+        Single(String),
+        Multiple(Vec<String>),
+    }
+
+    Ok(match Audience::deserialize(d)? {
+        Audience::Single(aud) => vec![aud],
+        Audience::Multiple(aud) => aud,
+    })
+}
+
 impl Claims {
     pub fn get(&self, key: &str) -> Option<serde_json::Value> {
         match key {
             "iss" => self.iss.clone().map(serde_json::Value::from),
             "sub" => self.sub.clone().map(serde_json::Value::from),
             "exp" => Some(serde_json::Value::from(self.exp)),
+            "aud" => Some(serde_json::Value::from(self.aud.clone())),
             _ => self.other.get(key).cloned(),
         }
     }
+
+    /// Get the value of a claim, possibly nested inside objects or arrays.
+    pub fn lookup(&self, path: &ClaimPath) -> Option<serde_json::Value> {
+        let value = self.get(path.claim())?;
+        path.resolve(&value).cloned()
+    }
+
+    /// All of the claims, as a JSON object.
+    pub fn to_map(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut map: serde_json::Map<_, _> = self
+            .other
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for key in ["iss", "sub", "exp", "aud"] {
+            if let Some(value) = self.get(key) {
+                map.insert(key.into(), value);
+            }
+        }
+        map
+    }
 }
 
 impl UnverifiedClaims {
@@ -46,105 +100,569 @@ impl UnverifiedClaims {
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct OpenIdConfig {
-    jwks_uri: String,
+/// A key that an ID token may be signed with.
+#[derive(Debug, Clone)]
+pub enum Key {
+    Jwk(Jwk),
+    PublicKey(PublicKey),
 }
 
-pub async fn load_jwks(issuer: &str) -> anyhow::Result<JwkSet> {
-    let url = format!("{}/.well-known/openid-configuration", issuer);
-    let config: OpenIdConfig = reqwest::get(url).await?.error_for_status()?.json().await?;
-    let jwks: JwkSet = reqwest::get(config.jwks_uri)
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
-    Ok(jwks)
+impl Key {
+    /// Check that a token signed with the given algorithm may be verified with this key.
+    ///
+    /// If the key does not specify an algorithm, any algorithm that is compatible with its type is
+    /// accepted.
+    pub fn check_algorithm(&self, algorithm: Algorithm) -> anyhow::Result<()> {
+        let expected = match self {
+            Key::Jwk(jwk) => {
+                check_key_type(jwk, algorithm)?;
+                key_algorithm(jwk)?
+            }
+            Key::PublicKey(key) => Some(key.algorithm),
+        };
+        if let Some(expected) = expected
+            && expected != algorithm
+        {
+            anyhow::bail!(
+                "Token algorithm {algorithm:?} does not match the key's algorithm {expected:?}"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn decoding_key(&self) -> anyhow::Result<DecodingKey> {
+        match self {
+            Key::Jwk(jwk) => Ok(DecodingKey::from_jwk(jwk)?),
+            Key::PublicKey(key) => Ok(key.key.clone()),
+        }
+    }
 }
 
-pub async fn resolve_key(issuer: &str, kid: &str) -> anyhow::Result<Jwk> {
-    let jwks = load_jwks(issuer).await?;
-    jwks.find(kid)
-        .cloned()
-        .ok_or_else(|| anyhow::anyhow!("Unknown key"))
+/// Verifies ID tokens, keeping track of the issuers' keys and of already exchanged tokens
+/// across requests.
+pub struct Verifier {
+    jwks: JwksCache,
+    replay: ReplayStore,
 }
 
-pub fn check_claims(policy: &Policy, claims: &Claims) -> Result<(), Error> {
-    if policy.required_claims.is_empty() {
-        return Err(Error::EmptyPolicyClaims);
-    }
+/// Check that the token was issued recently, and not in the future.
+pub fn check_token_age(claims: &Claims, issuer: &IssuerConfig) -> Result<(), Error> {
+    let Some(max_age) = issuer.max_token_age else {
+        return Ok(());
+    };
+    // NumericDate values may have a fractional part, which does not matter at this precision.
+    let Some(iat) = claims.get("iat").and_then(|iat| iat.as_f64()) else {
+        return Err(Error::InvalidClaim {
+            claim: "iat".into(),
+        });
+    };
+    let iat = iat as u64;
 
-    if claims.iss.as_deref() != Some(&policy.issuer) {
-        panic!("issuer is invalid - this should not be possible");
+    let now = jsonwebtoken::get_current_timestamp();
+    let leeway = issuer.leeway.as_secs();
+    if iat > now.saturating_add(leeway)
+        || iat.saturating_add(max_age.as_secs()).saturating_add(leeway) < now
+    {
+        return Err(Error::InvalidClaim {
+            claim: "iat".into(),
+        });
     }
+    Ok(())
+}
 
-    for (key, expected) in &policy.required_claims {
-        match claims.get(key) {
+fn check_required_claims(
+    required_claims: &HashMap<ClaimPath, ClaimMatcher>,
+    claims: &Claims,
+) -> Result<(), Error> {
+    for (key, expected) in required_claims {
+        match claims.lookup(key) {
             Some(actual) => {
-                if actual != *expected {
-                    return Err(Error::InvalidClaim { claim: key.into() });
+                if !expected.matches(&actual) {
+                    return Err(Error::ClaimMismatch {
+                        claim: key.to_string(),
+                        matcher: expected.to_string(),
+                    });
                 }
             }
+            None if expected.has_negation() => {
+                return Err(Error::UndecidableClaim {
+                    claim: key.to_string(),
+                });
+            }
             None => {
-                return Err(Error::InvalidClaim { claim: key.into() });
+                return Err(Error::InvalidClaim {
+                    claim: key.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn check_condition(condition: &Condition, claims: &Claims) -> Result<(), Error> {
+    match condition {
+        Condition::All(conditions) => conditions
+            .iter()
+            .try_for_each(|c| check_condition(c, claims)),
+        Condition::Any(conditions) => {
+            let mut errors = Vec::new();
+            for c in conditions {
+                match check_condition(c, claims) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => errors.push(err),
+                }
+            }
+            // A condition which could not be checked might have held.
+            if let Some(index) = errors.iter().position(|err| !err.is_mismatch()) {
+                return Err(errors.swap_remove(index));
+            }
+            Err(Error::ConditionNotMet(format!(
+                "none of `any` matched ({})",
+                errors.iter().join("; ")
+            )))
+        }
+        Condition::Not(condition) => {
+            // A missing claim or a failed expression does not count as a successful negation,
+            // otherwise omitting a claim from the token would be enough to satisfy the policy.
+            check_decidable(condition, claims)?;
+            match check_condition(condition, claims) {
+                Ok(()) => Err(Error::ConditionNotMet("`not` condition matched".into())),
+                Err(_) => Ok(()),
             }
         }
+        Condition::Claims(required_claims) => check_required_claims(required_claims, claims),
+        Condition::Cel(expression) => {
+            if evaluate_expression(expression, claims)? {
+                Ok(())
+            } else {
+                Err(Error::ConditionNotMet(format!(
+                    "expression {expression} evaluated to false"
+                )))
+            }
+        }
+    }
+}
+
+/// Check that all of the claims referenced anywhere in the condition are present, and that all of
+/// its expressions can be evaluated.
+fn check_decidable(condition: &Condition, claims: &Claims) -> Result<(), Error> {
+    match condition {
+        Condition::All(conditions) | Condition::Any(conditions) => conditions
+            .iter()
+            .try_for_each(|c| check_decidable(c, claims)),
+        Condition::Not(condition) => check_decidable(condition, claims),
+        Condition::Claims(required_claims) => {
+            for key in required_claims.keys() {
+                if claims.lookup(key).is_none() {
+                    return Err(Error::UndecidableClaim {
+                        claim: key.to_string(),
+                    });
+                }
+            }
+            Ok(())
+        }
+        Condition::Cel(expression) => evaluate_expression(expression, claims).map(drop),
+    }
+}
+
+fn evaluate_expression(expression: &Expression, claims: &Claims) -> Result<bool, Error> {
+    expression
+        .evaluate(claims)
+        .map_err(|err| Error::ExpressionFailed {
+            expression: expression.to_string(),
+            reason: format!("{err:#}"),
+        })
+}
+
+pub fn check_claims(policy: &Policy, claims: &Claims) -> Result<(), Error> {
+    if !policy.has_constraints() {
+        return Err(Error::EmptyPolicyClaims);
+    }
+
+    if claims.iss.as_deref() != Some(&policy.issuer) {
+        panic!("issuer is invalid - this should not be possible");
+    }
+
+    check_required_claims(&policy.required_claims, claims)?;
+    if let Some(condition) = &policy.condition {
+        check_condition(condition, claims)?;
     }
     Ok(())
 }
 
-pub fn key_algorithm(key: &Jwk) -> anyhow::Result<Algorithm> {
+/// Check whether a deny policy applies to the token.
+///
+/// Only a plain mismatch counts as the policy not applying. If the policy cannot be checked, for
+/// example because an expression fails or a negated claim is missing, the token cannot be known
+/// not to be denied and this is an error.
+fn is_denied(policy: &Policy, claims: &Claims) -> Result<bool, Error> {
+    match check_claims(policy, claims) {
+        Ok(()) => Ok(true),
+        Err(err) if err.is_mismatch() => {
+            tracing::debug!("deny policy did not match: {err}");
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Get the algorithm specified by the key, if any.
+pub fn key_algorithm(key: &Jwk) -> anyhow::Result<Option<Algorithm>> {
     match key.common.key_algorithm {
-        Some(KeyAlgorithm::HS256) => Ok(Algorithm::HS256),
-        Some(KeyAlgorithm::HS384) => Ok(Algorithm::HS384),
-        Some(KeyAlgorithm::HS512) => Ok(Algorithm::HS512),
-        Some(KeyAlgorithm::ES256) => Ok(Algorithm::ES256),
-        Some(KeyAlgorithm::ES384) => Ok(Algorithm::ES384),
-        Some(KeyAlgorithm::RS256) => Ok(Algorithm::RS256),
-        Some(KeyAlgorithm::RS384) => Ok(Algorithm::RS384),
-        Some(KeyAlgorithm::RS512) => Ok(Algorithm::RS512),
-        Some(KeyAlgorithm::PS256) => Ok(Algorithm::PS256),
-        Some(KeyAlgorithm::PS384) => Ok(Algorithm::PS384),
-        Some(KeyAlgorithm::PS512) => Ok(Algorithm::PS512),
-        Some(KeyAlgorithm::EdDSA) => Ok(Algorithm::EdDSA),
+        Some(KeyAlgorithm::HS256) => Ok(Some(Algorithm::HS256)),
+        Some(KeyAlgorithm::HS384) => Ok(Some(Algorithm::HS384)),
+        Some(KeyAlgorithm::HS512) => Ok(Some(Algorithm::HS512)),
+        Some(KeyAlgorithm::ES256) => Ok(Some(Algorithm::ES256)),
+        Some(KeyAlgorithm::ES384) => Ok(Some(Algorithm::ES384)),
+        Some(KeyAlgorithm::RS256) => Ok(Some(Algorithm::RS256)),
+        Some(KeyAlgorithm::RS384) => Ok(Some(Algorithm::RS384)),
+        Some(KeyAlgorithm::RS512) => Ok(Some(Algorithm::RS512)),
+        Some(KeyAlgorithm::PS256) => Ok(Some(Algorithm::PS256)),
+        Some(KeyAlgorithm::PS384) => Ok(Some(Algorithm::PS384)),
+        Some(KeyAlgorithm::PS512) => Ok(Some(Algorithm::PS512)),
+        Some(KeyAlgorithm::EdDSA) => Ok(Some(Algorithm::EdDSA)),
         Some(_) => anyhow::bail!("Unsupported key algorithm"),
-        None => anyhow::bail!("Key does not specify an algorithm"),
+        None => Ok(None),
     }
 }
 
-pub async fn verify<'a>(token: &str, config: &'a Config) -> Result<(Claims, &'a Policy), Error> {
-    let unverified_token = UnverifiedClaims::decode(&token).context("Cannot decode token")?;
-    let Some(candidate_policies) = config.policies.get(&unverified_token.claims.iss) else {
-        return Err(Error::InvalidClaim {
-            claim: "iss".into(),
-        });
-    };
-    let Some(kid) = unverified_token.header.kid else {
-        return Err(Error::Other(anyhow::anyhow!(
-            "Token header does not have a key ID"
-        )));
+pub fn is_symmetric(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    )
+}
+
+/// Check that the key's type, and curve if applicable, can be used with the given algorithm.
+pub fn check_key_type(key: &Jwk, algorithm: Algorithm) -> anyhow::Result<()> {
+    let compatible = match &key.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(
+            algorithm,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(params) => matches!(
+            (&params.curve, algorithm),
+            (EllipticCurve::P256, Algorithm::ES256) | (EllipticCurve::P384, Algorithm::ES384)
+        ),
+        AlgorithmParameters::OctetKeyPair(params) => {
+            params.curve == EllipticCurve::Ed25519 && algorithm == Algorithm::EdDSA
+        }
+        AlgorithmParameters::OctetKey(_) => is_symmetric(algorithm),
     };
-    let key = resolve_key(&unverified_token.claims.iss, &kid).await?;
+    if !compatible {
+        anyhow::bail!("Key type is not compatible with algorithm {algorithm:?}");
+    }
+    Ok(())
+}
+
+fn decode_with_keys(
+    token: &str,
+    keys: &[Key],
+    algorithm: Algorithm,
+    validation: &Validation,
+) -> anyhow::Result<TokenData<Claims>> {
+    let mut result = Err(anyhow::anyhow!("No key matches the token"));
+    for key in keys {
+        if let Err(err) = key.check_algorithm(algorithm) {
+            result = Err(err);
+            continue;
+        }
+        let decoding_key = match key.decoding_key() {
+            Ok(decoding_key) => decoding_key,
+            Err(err) => {
+                result = Err(err);
+                continue;
+            }
+        };
+        match jsonwebtoken::decode(token, &decoding_key, validation) {
+            Ok(decoded) => return Ok(decoded),
+            // This is the wrong key, but another one may be the right one.
+            Err(err) if *err.kind() == ErrorKind::InvalidSignature => {
+                result = Err(anyhow::Error::from(err).context("while decoding ID token"));
+            }
+            Err(err) => return Err(anyhow::Error::from(err).context("while decoding ID token")),
+        }
+    }
+    result
+}
+
+impl Verifier {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Verifier {
+            jwks: JwksCache::default(),
+            replay: ReplayStore::open(&config.replay_store)?,
+        })
+    }
 
-    let mut validation = Validation::new(key_algorithm(&key)?);
-    validation.set_audience(&[&config.audience]);
-    validation.required_spec_claims.insert("exp".into());
-    validation.required_spec_claims.insert("aud".into());
-    validation.validate_aud = true;
-    validation.validate_exp = true;
-    validation.validate_nbf = true;
+    /// If the token's issuer only allows single-use tokens, record the token as used, failing if
+    /// it had already been used before.
+    ///
+    /// This must only be called once everything else about the exchange has succeeded, so that a
+    /// failed exchange does not use up the token.
+    ///
+    /// Tokens are identified by their `jti` claim if they have one, or by a hash of the whole
+    /// token otherwise.
+    pub async fn check_replay(
+        &self,
+        token: &str,
+        claims: &Claims,
+        config: &Config,
+    ) -> Result<(), Error> {
+        let Some(issuer) = claims.iss.as_ref().and_then(|iss| config.issuers.get(iss)) else {
+            return Err(Error::InvalidClaim {
+                claim: "iss".into(),
+            });
+        };
+        if !issuer.single_use {
+            return Ok(());
+        }
 
-    let decoding_key = DecodingKey::from_jwk(&key).map_err(anyhow::Error::from)?;
-    let decoded: TokenData<Claims> = jsonwebtoken::decode(token, &decoding_key, &validation)
-        .context("while decoding ID token")?;
+        let mut hasher = Sha256::new();
+        match claims.get("jti") {
+            Some(jti) => {
+                hasher.update(b"jti\0");
+                hasher.update(issuer.issuer.as_bytes());
+                hasher.update(b"\0");
+                // The claim should be a string, but any other value identifies the token as well.
+                match jti {
+                    serde_json::Value::String(jti) => hasher.update(jti.as_bytes()),
+                    jti => hasher.update(jti.to_string().as_bytes()),
+                }
+            }
+            None => {
+                hasher.update(b"token\0");
+                hasher.update(token.as_bytes());
+            }
+        }
+        let id = BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize());
 
-    let mut errors = Vec::new();
-    for policy in candidate_policies {
-        match check_claims(&policy, &decoded.claims) {
-            Ok(()) => return Ok((decoded.claims, policy)),
-            Err(err) => errors.push(err),
+        // The token remains valid for a little while after its expiry.
+        let expires_at = claims.exp.saturating_add(issuer.leeway.as_secs());
+        if self.replay.insert(id, expires_at).await? {
+            Ok(())
+        } else {
+            Err(Error::TokenReplayed)
         }
     }
 
-    Err(Error::NoValidPolicy(errors).into())
+    /// Find the issuer's keys with the given key ID, or all of its keys if no key ID is given.
+    pub async fn resolve_keys(
+        &self,
+        issuer: &IssuerConfig,
+        kid: Option<&str>,
+    ) -> Result<Vec<Key>, Error> {
+        let keys = match &issuer.keys {
+            Some(IssuerKeys::Jwks(jwks) | IssuerKeys::JwksFile(jwks)) => {
+                matching_keys(jwks, kid).into_iter().map(Key::Jwk).collect()
+            }
+            Some(IssuerKeys::PublicKey(key)) => {
+                if kid.is_none() || key.kid.is_none() || key.kid.as_deref() == kid {
+                    vec![Key::PublicKey(key.clone())]
+                } else {
+                    vec![]
+                }
+            }
+            None => {
+                let keys = self.jwks.find(issuer, kid).await?;
+                keys.into_iter().map(Key::Jwk).collect()
+            }
+        };
+        if keys.is_empty() {
+            return Err(Error::Other(anyhow::anyhow!("Unknown key")));
+        }
+        Ok(keys)
+    }
+
+    pub async fn verify<'a>(
+        &self,
+        token: &str,
+        config: &'a Config,
+    ) -> Result<(Claims, Vec<&'a Policy>), Error> {
+        let unverified_token = UnverifiedClaims::decode(token).context("Cannot decode token")?;
+        let iss = &unverified_token.claims.iss;
+        let (Some(candidate_policies), Some(issuer)) =
+            (config.policies.get(iss), config.issuers.get(iss))
+        else {
+            return Err(Error::InvalidClaim {
+                claim: "iss".into(),
+            });
+        };
+        let algorithm = unverified_token.header.alg;
+        if !issuer.allows_algorithm(algorithm) {
+            return Err(Error::Other(anyhow::anyhow!(
+                "Algorithm {algorithm:?} is not allowed for this issuer"
+            )));
+        }
+
+        // Tokens without a key ID are tried against every key that may have signed them.
+        let kid = unverified_token.header.kid.as_deref();
+        let keys = self.resolve_keys(issuer, kid).await?;
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = issuer.leeway.as_secs();
+        validation.set_audience(&[&config.audience]);
+        validation.required_spec_claims.insert("exp".into());
+        validation.required_spec_claims.insert("aud".into());
+        validation.validate_aud = true;
+        validation.validate_exp = true;
+        validation.validate_nbf = true;
+
+        let decoded = decode_with_keys(token, &keys, algorithm, &validation)?;
+        check_token_age(&decoded.claims, issuer)?;
+
+        let (deny_policies, allow_policies): (Vec<_>, Vec<_>) = candidate_policies
+            .iter()
+            .partition(|p| p.effect == Effect::Deny);
+        for policy in deny_policies {
+            let _span = tracing::info_span!("policy", policy = policy.identifier()).entered();
+            let denied = is_denied(policy, &decoded.claims)
+                .inspect_err(|err| tracing::warn!("could not check deny policy: {err}"))?;
+            if denied {
+                tracing::warn!("token denied");
+                return Err(Error::Denied {
+                    policy: policy.label(),
+                });
+            }
+        }
+
+        let mut matched = Vec::new();
+        let mut errors = Vec::new();
+        for policy in allow_policies {
+            let _span = tracing::info_span!("policy", policy = policy.identifier()).entered();
+            match check_claims(policy, &decoded.claims) {
+                Ok(()) => {
+                    tracing::info!("policy matched");
+                    matched.push(policy);
+                    if config.match_mode == MatchMode::First {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::debug!("policy did not match: {err}");
+                    errors.push(PolicyFailure {
+                        policy: policy.label(),
+                        error: err,
+                    });
+                }
+            }
+        }
+
+        if matched.is_empty() {
+            return Err(Error::NoValidPolicy(errors));
+        }
+        Ok((decoded.claims, matched))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn check(condition: serde_json::Value, claims: serde_json::Value) -> Result<(), Error> {
+        let condition: Condition = serde_json::from_value(condition).unwrap();
+        let mut claims = claims;
+        claims["exp"] = json!(0);
+        let claims: Claims = serde_json::from_value(claims).unwrap();
+        check_condition(&condition, &claims)
+    }
+
+    fn deny(policy: &str, claims: serde_json::Value) -> Result<bool, Error> {
+        let policy = format!("issuer = \"https://issuer\"\neffect = \"deny\"\n{policy}");
+        let policy: Policy = toml::from_str(&policy).unwrap();
+        let mut claims = claims;
+        claims["iss"] = json!("https://issuer");
+        claims["exp"] = json!(0);
+        is_denied(&policy, &serde_json::from_value(claims).unwrap())
+    }
+
+    #[test]
+    fn deny_fails_when_expression_fails() {
+        let policy = r#"condition.any = [{ cel = "claims.foo.startsWith('x')" }]"#;
+        assert!(deny(policy, json!({ "foo": "xyz" })).unwrap());
+        assert!(!deny(policy, json!({ "foo": "abc" })).unwrap());
+        assert!(matches!(
+            deny(policy, json!({})),
+            Err(Error::ExpressionFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn deny_fails_when_negated_claim_is_missing() {
+        for policy in [
+            r#"condition.not.claims.repository_owner_id = "1234""#,
+            r#"required_claims.repository_owner_id = { not = "1234" }"#,
+        ] {
+            assert!(deny(policy, json!({ "repository_owner_id": "5678" })).unwrap());
+            assert!(!deny(policy, json!({ "repository_owner_id": "1234" })).unwrap());
+            assert!(matches!(
+                deny(policy, json!({})),
+                Err(Error::UndecidableClaim { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn deny_does_not_match_missing_claim() {
+        let policy = r#"required_claims.actor = "dependabot[bot]""#;
+        assert!(!deny(policy, json!({})).unwrap());
+    }
+
+    #[test]
+    fn not_matches_other_values() {
+        let condition = json!({ "not": { "claims": { "ref": "refs/heads/experimental" } } });
+        assert!(check(condition.clone(), json!({ "ref": "refs/heads/main" })).is_ok());
+        assert!(matches!(
+            check(condition, json!({ "ref": "refs/heads/experimental" })),
+            Err(Error::ConditionNotMet(_))
+        ));
+    }
+
+    #[test]
+    fn not_requires_claims_in_any() {
+        let condition = json!({ "not": { "any": [
+            { "claims": { "ref": "refs/heads/experimental" } },
+            { "claims": { "actor": "dependabot[bot]" } },
+        ] } });
+        assert!(
+            check(
+                condition.clone(),
+                json!({ "ref": "refs/heads/main", "actor": "me" })
+            )
+            .is_ok()
+        );
+        assert!(matches!(
+            check(condition, json!({ "actor": "me" })),
+            Err(Error::UndecidableClaim { claim }) if claim == "ref"
+        ));
+    }
+
+    #[test]
+    fn not_requires_claims_in_all() {
+        let condition = json!({ "not": { "all": [
+            { "claims": { "ref": "refs/heads/experimental" } },
+            { "claims": { "actor": "dependabot[bot]" } },
+        ] } });
+        assert!(matches!(
+            check(condition, json!({ "ref": "refs/heads/main" })),
+            Err(Error::UndecidableClaim { claim }) if claim == "actor"
+        ));
+    }
+
+    #[test]
+    fn not_requires_expressions_to_evaluate() {
+        let condition = json!({ "not": { "any": [
+            { "cel": "claims.ref == 'refs/heads/experimental'" },
+        ] } });
+        assert!(check(condition.clone(), json!({ "ref": "refs/heads/main" })).is_ok());
+        assert!(matches!(
+            check(condition, json!({})),
+            Err(Error::ExpressionFailed { .. })
+        ));
+    }
 }